@@ -0,0 +1,234 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use ipnet::IpNet;
+use regex::Regex;
+
+use crate::{
+    app::parse_cidrs,
+    config::{GatewayIpAccessConfig, GatewayRouteAccessConfig},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessDecision {
+    Allowed,
+    RouteDenied,
+    MethodDenied,
+    UnknownApplication,
+    IpDenied,
+}
+
+impl AccessDecision {
+    /// The metric label of the decision
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessDecision::Allowed => "allowed",
+            AccessDecision::RouteDenied => "route_denied",
+            AccessDecision::MethodDenied => "method_denied",
+            AccessDecision::UnknownApplication => "unknown_application",
+            AccessDecision::IpDenied => "ip_denied",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            AccessDecision::Allowed => "access allowed",
+            AccessDecision::RouteDenied => "application may not access this route",
+            AccessDecision::MethodDenied => "application may not use this method on this route",
+            AccessDecision::UnknownApplication => "route requires a known application",
+            AccessDecision::IpDenied => "client address not allowed",
+        }
+    }
+}
+
+struct RouteAccess {
+    route: Regex,
+    /// Upper-case methods, any method when `None`
+    methods: Option<Vec<String>>,
+}
+
+/// The routes an application may call, by name or glob, e.g., "orders-*"
+pub struct RouteAcl(Vec<RouteAccess>);
+
+impl RouteAcl {
+    pub fn new(configs: &[GatewayRouteAccessConfig]) -> Self {
+        Self(
+            configs
+                .iter()
+                .map(|config| RouteAccess {
+                    route: glob_regex(&config.route),
+                    methods: config
+                        .methods
+                        .as_ref()
+                        .map(|methods| methods.iter().map(|m| m.to_uppercase()).collect()),
+                })
+                .collect(),
+        )
+    }
+
+    pub fn check(&self, route: &str, method: &str) -> AccessDecision {
+        let mut decision = AccessDecision::RouteDenied;
+        for access in self.0.iter().filter(|access| access.route.is_match(route)) {
+            match &access.methods {
+                Some(methods) if !methods.iter().any(|m| m == method) => {
+                    decision = AccessDecision::MethodDenied;
+                }
+                _ => return AccessDecision::Allowed,
+            }
+        }
+        decision
+    }
+}
+
+/// `*` matches any run of characters and `?` a single one
+fn glob_regex(glob: &str) -> Regex {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("^{}$", pattern)).expect("an escaped glob is a valid regex")
+}
+
+/// CIDR ranges a client must be in, denied ranges win over allowed ones
+#[derive(Debug, Default)]
+pub struct IpAccessList {
+    /// Any client is allowed when empty
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpAccessList {
+    pub fn parse(config: &GatewayIpAccessConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: parse_cidrs(config.allow.as_deref().unwrap_or_default())?,
+            deny: parse_cidrs(config.deny.as_deref().unwrap_or_default())?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Clients without an ip address only pass lists without allowed ranges
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip.map(|ip| ip.to_canonical()) else {
+            return self.allow.is_empty();
+        };
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    pub fn to_config(&self) -> GatewayIpAccessConfig {
+        let strings = |nets: &[IpNet]| nets.iter().map(|net| net.to_string()).collect();
+        GatewayIpAccessConfig {
+            allow: Some(strings(&self.allow)),
+            deny: Some(strings(&self.deny)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IpAccessScope {
+    Global,
+    Route(String),
+    Application(String),
+}
+
+impl IpAccessScope {
+    /// "global", "route" or "application", the latter two need a name
+    pub fn parse(scope: &str, name: Option<&str>) -> Option<Self> {
+        match (scope, name) {
+            ("global", _) => Some(IpAccessScope::Global),
+            ("route", Some(name)) => Some(IpAccessScope::Route(name.to_string())),
+            ("application", Some(name)) => Some(IpAccessScope::Application(name.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// The ip access lists of all scopes, kept apart from routes and
+/// applications so they can be replaced live
+#[derive(Default)]
+pub struct IpAccessLists(RwLock<HashMap<IpAccessScope, Arc<IpAccessList>>>);
+
+impl IpAccessLists {
+    pub fn get(&self, scope: &IpAccessScope) -> Option<Arc<IpAccessList>> {
+        self.0.read().unwrap().get(scope).cloned()
+    }
+
+    pub fn set(&self, scope: IpAccessScope, list: IpAccessList) {
+        let mut lists = self.0.write().unwrap();
+        if list.is_empty() {
+            lists.remove(&scope);
+        } else {
+            lists.insert(scope, Arc::new(list));
+        }
+    }
+
+    pub fn remove(&self, scope: &IpAccessScope) {
+        self.0.write().unwrap().remove(scope);
+    }
+
+    pub fn allows(&self, scope: &IpAccessScope, ip: Option<IpAddr>) -> bool {
+        self.get(scope).is_none_or(|list| list.allows(ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route_acl() {
+        let access = |route: &str, methods: Option<&[&str]>| GatewayRouteAccessConfig {
+            route: route.to_string(),
+            methods: methods.map(|m| m.iter().map(|m| m.to_string()).collect()),
+        };
+        let acl = RouteAcl::new(&[
+            access("orders-*", Some(&["get"])),
+            access("orders-admin", None),
+            access("users", Some(&["GET", "POST"])),
+            access("api.v?", None),
+        ]);
+
+        assert_eq!(acl.check("orders-v1", "GET"), AccessDecision::Allowed);
+        assert_eq!(
+            acl.check("orders-v1", "DELETE"),
+            AccessDecision::MethodDenied
+        );
+        assert_eq!(acl.check("orders-admin", "DELETE"), AccessDecision::Allowed);
+        assert_eq!(acl.check("users", "POST"), AccessDecision::Allowed);
+        assert_eq!(acl.check("users-v2", "GET"), AccessDecision::RouteDenied);
+        assert_eq!(acl.check("api.v2", "GET"), AccessDecision::Allowed);
+        assert_eq!(acl.check("api-v2", "GET"), AccessDecision::RouteDenied);
+        assert_eq!(
+            RouteAcl::new(&[]).check("users", "GET"),
+            AccessDecision::RouteDenied
+        );
+    }
+
+    #[test]
+    fn test_ip_access_list() {
+        let list = IpAccessList::parse(&GatewayIpAccessConfig {
+            allow: Some(vec!["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()]),
+            deny: Some(vec!["10.0.0.1".to_string()]),
+        })
+        .unwrap();
+
+        assert!(list.allows("10.1.2.3".parse().ok()));
+        assert!(list.allows("::ffff:10.1.2.3".parse().ok()));
+        assert!(list.allows("2001:db8::1".parse().ok()));
+        assert!(!list.allows("10.0.0.1".parse().ok()));
+        assert!(!list.allows("192.168.0.1".parse().ok()));
+        assert!(!list.allows(None));
+
+        let lists = IpAccessLists::default();
+        let scope = IpAccessScope::Route("admin".to_string());
+        assert!(lists.allows(&scope, "192.168.0.1".parse().ok()));
+        lists.set(scope.clone(), list);
+        assert!(!lists.allows(&scope, "192.168.0.1".parse().ok()));
+        assert!(lists.allows(&IpAccessScope::Global, "192.168.0.1".parse().ok()));
+        lists.set(scope.clone(), IpAccessList::default());
+        assert!(lists.get(&scope).is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use axum::http::{header, HeaderName, HeaderValue, Method, StatusCode};
+use bytes::Bytes;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{
+    compression::{Compression, Encoder},
+    config::{GatewayRedirectConfig, GatewayRespondConfig},
+    grpc::{is_grpc_request, respond_grpc_error, GrpcStatus},
+    headers::{HeaderTemplate, TemplateVars},
+    proxy::error_response,
+    static_files::StaticFiles,
+};
+
+/// The response rules of the route, applied to the response headers
+pub type ResponseRules<'a> =
+    &'a (dyn Fn(&RequestHeader, &mut ResponseHeader) -> pingora::Result<()> + Sync);
+
+/// Writes the answer of an action, the response rules and compression of
+/// the route apply to it as they do to proxied responses
+pub struct ActionResponse<'a> {
+    session: &'a mut Session,
+    rules: ResponseRules<'a>,
+    compression: Option<&'a Compression>,
+    encoder: Option<Encoder>,
+}
+
+impl<'a> ActionResponse<'a> {
+    pub fn new(
+        session: &'a mut Session,
+        rules: ResponseRules<'a>,
+        compression: Option<&'a Compression>,
+    ) -> Self {
+        Self {
+            session,
+            rules,
+            compression,
+            encoder: None,
+        }
+    }
+
+    pub fn req_header(&self) -> &RequestHeader {
+        self.session.req_header()
+    }
+
+    pub async fn write_header(
+        &mut self,
+        mut header: ResponseHeader,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        (self.rules)(self.session.req_header(), &mut header)?;
+        if !end_of_stream && let Some(compression) = self.compression {
+            self.encoder = compression.encoder(self.session.req_header(), &mut header);
+        }
+        self.session
+            .write_response_header(Box::new(header), end_of_stream)
+            .await
+    }
+
+    pub async fn write_body(&mut self, body: Bytes, end_of_stream: bool) -> pingora::Result<()> {
+        let body = match &mut self.encoder {
+            Some(encoder) => encoder.encode(&body, end_of_stream)?,
+            None => body,
+        };
+        self.session
+            .write_response_body(Some(body), end_of_stream)
+            .await
+    }
+
+    /// Answer with an error like [`crate::proxy::respond_error`]
+    pub async fn error(&mut self, code: u16, message: &str) -> pingora::Result<()> {
+        if is_grpc_request(self.session) {
+            let status = GrpcStatus::from_http_status(code);
+            return respond_grpc_error(self.session, status, message).await;
+        }
+        let (header, body) = error_response(code, message)?;
+        self.session.set_keepalive(None);
+        self.write_header(header, false).await?;
+        self.write_body(body, true).await
+    }
+}
+
+/// An answer the gateway gives itself instead of proxying to an upstream
+pub enum RouteAction {
+    Redirect {
+        status: u16,
+        target: HeaderTemplate,
+    },
+    Respond {
+        status: u16,
+        headers: Vec<(HeaderName, HeaderValue)>,
+        body: Bytes,
+    },
+    StaticFiles(StaticFiles),
+}
+
+impl RouteAction {
+    pub fn redirect(config: &GatewayRedirectConfig) -> anyhow::Result<Self> {
+        let status = config.status.unwrap_or(302);
+        if !StatusCode::from_u16(status).is_ok_and(|s| s.is_redirection()) {
+            anyhow::bail!("{} is no redirect status", status);
+        }
+        Ok(RouteAction::Redirect {
+            status,
+            target: HeaderTemplate::parse(&config.target),
+        })
+    }
+
+    pub fn respond(config: &GatewayRespondConfig) -> anyhow::Result<Self> {
+        let status = config.status.unwrap_or(200);
+        StatusCode::from_u16(status)?;
+        let body = match (&config.body, &config.file) {
+            (Some(body), None) => Bytes::from(body.to_string()),
+            (None, Some(path)) => Bytes::from(std::fs::read(path)?),
+            (None, None) => Bytes::new(),
+            (Some(_), Some(_)) => anyhow::bail!("only one of body and file may be set"),
+        };
+        let headers = config
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                Ok((
+                    HeaderName::try_from(name.as_str())?,
+                    HeaderValue::try_from(value.as_str())?,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(RouteAction::Respond {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    /// `path` is the request path after the route rewrite
+    pub async fn answer(
+        &self,
+        response: &mut ActionResponse<'_>,
+        path: &str,
+        vars: &TemplateVars<'_>,
+    ) -> pingora::Result<()> {
+        let (header, body) = match self {
+            RouteAction::StaticFiles(files) => return files.serve(response, path).await,
+            RouteAction::Redirect { status, target } => {
+                let mut header = ResponseHeader::build(*status, Some(2))?;
+                header.insert_header(header::LOCATION, target.render(vars))?;
+                header.insert_header(header::CONTENT_LENGTH, "0")?;
+                (header, Bytes::new())
+            }
+            RouteAction::Respond {
+                status,
+                headers,
+                body,
+            } => {
+                let mut header = ResponseHeader::build(*status, Some(headers.len() + 1))?;
+                for (name, value) in headers {
+                    header.append_header(name.clone(), value.clone())?;
+                }
+                header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+                (header, body.clone())
+            }
+        };
+        // the length of a HEAD answer is announced but never sent
+        let head = response.req_header().method == Method::HEAD;
+        if head || body.is_empty() {
+            return response.write_header(header, true).await;
+        }
+        response.write_header(header, false).await?;
+        response.write_body(body, true).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_route_actions() {
+        let redirect = |status: Option<u16>| {
+            RouteAction::redirect(&GatewayRedirectConfig {
+                status,
+                target: "https://new.example.com/$1".to_string(),
+            })
+        };
+        assert!(redirect(Some(200)).is_err());
+        let Ok(RouteAction::Redirect { status, target }) = redirect(None) else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(status, 302);
+        let captures = HashMap::from([("1".to_string(), "docs".to_string())]);
+        let vars = TemplateVars {
+            client_ip: None,
+            app_id: None,
+            route: "old",
+            upstream: None,
+            captures: &captures,
+        };
+        assert_eq!(target.render(&vars), "https://new.example.com/docs");
+
+        let respond = |body: Option<&str>, file: Option<&str>| {
+            RouteAction::respond(&GatewayRespondConfig {
+                status: Some(503),
+                headers: Some(HashMap::from([(
+                    "Retry-After".to_string(),
+                    "120".to_string(),
+                )])),
+                body: body.map(str::to_string),
+                file: file.map(str::to_string),
+            })
+        };
+        let Ok(RouteAction::Respond {
+            status,
+            headers,
+            body,
+        }) = respond(Some("maintenance"), None)
+        else {
+            panic!("expected a response");
+        };
+        assert_eq!((status, body.as_ref()), (503, b"maintenance".as_ref()));
+        assert_eq!(headers.len(), 1);
+        assert!(respond(Some("a"), Some("/tmp/b")).is_err());
+        assert!(respond(None, Some("/nonexistent/robots.txt")).is_err());
+    }
+}
@@ -1,25 +1,46 @@
 use axum::{
+    http::StatusCode,
     response::Html,
     routing::{get, post},
     Json, Router,
 };
 use pingora::server::ShutdownWatch;
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use tracing::{error, info};
 
 use crate::{
+    acl::IpAccessScope,
     app::{self, Application, LbInfo, LbMatchRuleInfo, LbRewriteInfo},
+    cache,
+    config::{
+        GatewayCacheConfig, GatewayCompressionConfig, GatewayCorsConfig, GatewayCredentialsConfig,
+        GatewayForwardAuthConfig, GatewayGrpcConfig, GatewayHeadersConfig, GatewayIpAccessConfig,
+        GatewayJwtConfig, GatewayProxyProtocolConfig, GatewayQueryRewriteConfig,
+        GatewayQuotaConfig, GatewayRateLimitConfig, GatewayRedirectConfig, GatewayRespondConfig,
+        GatewayRouteAccessConfig, GatewayStaticFilesConfig, GatewayStreamingConfig,
+    },
+    metrics,
+    quota::QuotaUsage,
     store,
 };
 
 pub async fn start_admin_server(mut shutdown: ShutdownWatch) -> anyhow::Result<()> {
     let app = Router::new()
         .route("/healthz", get(handler))
+        .route("/metrics", get(get_metrics))
         .route("/app/add", post(add_application))
         .route("/app/update", post(update_application))
         .route("/app/remove", post(remove_application))
         .route("/app/get", post(get_application))
-        .route("/lb/add", post(add_lb));
+        .route("/app/quota/usage", post(get_quota_usage))
+        .route("/app/quota/reset", post(reset_quota))
+        .route("/app/key/issue", post(issue_api_key))
+        .route("/app/key/revoke", post(revoke_api_key))
+        .route("/app/key/list", post(list_api_keys))
+        .route("/lb/add", post(add_lb))
+        .route("/ip_access/get", post(get_ip_access))
+        .route("/ip_access/update", post(update_ip_access))
+        .route("/cache/purge", post(purge_cache));
 
     // run it
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
@@ -43,11 +64,24 @@ async fn handler() -> Html<&'static str> {
     Html("<h1>Hello, World!</h1>")
 }
 
+async fn get_metrics() -> String {
+    metrics::gather()
+}
+
 #[derive(Deserialize, Serialize)]
 struct ApplicationRequest {
     app_id: String,
     limit_interval_seconds: u64,
     limit: u32,
+    algorithm: Option<String>,
+    burst: Option<u32>,
+    distributed: Option<bool>,
+    quota: Option<GatewayQuotaConfig>,
+    max_concurrent: Option<u32>,
+    max_concurrent_queue_ms: Option<u64>,
+    credentials: Option<GatewayCredentialsConfig>,
+    routes: Option<Vec<GatewayRouteAccessConfig>>,
+    ip_access: Option<GatewayIpAccessConfig>,
 }
 
 impl Into<Application> for ApplicationRequest {
@@ -56,17 +90,112 @@ impl Into<Application> for ApplicationRequest {
             app_id: self.app_id,
             limit_interval_seconds: self.limit_interval_seconds,
             limit: self.limit,
+            algorithm: self.algorithm,
+            burst: self.burst,
+            distributed: self.distributed,
+            quota: self.quota,
+            max_concurrent: self.max_concurrent,
+            max_concurrent_queue_ms: self.max_concurrent_queue_ms,
+            credentials: self.credentials,
+            routes: self.routes,
+            ip_access: self.ip_access,
         }
     }
 }
 
+#[derive(Deserialize, Serialize)]
+struct ApplicationIdRequest {
+    app_id: String,
+}
+
+#[derive(Serialize)]
+struct QuotaUsageResponse {
+    app_id: String,
+    #[serde(flatten)]
+    usage: QuotaUsage,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ApiKeyRequest {
+    app_id: String,
+    key_id: String,
+}
+
+#[derive(Serialize)]
+struct ApiKeyListResponse {
+    app_id: String,
+    key_ids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ApiKeyResponse {
+    app_id: String,
+    key_id: String,
+    /// Only shown once, the gateway keeps the hash
+    api_key: String,
+    hash: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct IpAccessRequest {
+    /// "global", "route" or "application"
+    scope: String,
+    /// The route name or application id
+    name: Option<String>,
+    #[serde(flatten)]
+    ip_access: GatewayIpAccessConfig,
+}
+
+impl IpAccessRequest {
+    fn scope(&self) -> Result<IpAccessScope, (StatusCode, &'static str)> {
+        IpAccessScope::parse(&self.scope, self.name.as_deref())
+            .ok_or((StatusCode::BAD_REQUEST, "Invalid ip access scope"))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachePurgeRequest {
+    /// Every route when unset
+    route: Option<String>,
+    /// The path and query of a response, e.g., "/items?page=2", with all
+    /// its variants
+    key: Option<String>,
+    /// Purges every response whose key starts with it
+    prefix: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CachePurgeResponse {
+    purged: usize,
+}
+
 #[derive(Deserialize, Serialize)]
 struct GatewayLbRequest {
     name: String,
     match_rule: GatewayLbMatchRule,
     rewrite: Option<GatewayRewrite>,
+    #[serde(default)]
     service_discovery: String,
     static_upstream: Option<Vec<String>>,
+    protocol: Option<String>,
+    listen: Option<String>,
+    proxy_protocol: Option<GatewayProxyProtocolConfig>,
+    upstream_proxy_protocol: Option<String>,
+    streaming: Option<GatewayStreamingConfig>,
+    grpc: Option<GatewayGrpcConfig>,
+    rate_limit: Option<GatewayRateLimitConfig>,
+    jwt: Option<GatewayJwtConfig>,
+    require_application: Option<bool>,
+    forward_auth: Option<GatewayForwardAuthConfig>,
+    ip_access: Option<GatewayIpAccessConfig>,
+    request_headers: Option<GatewayHeadersConfig>,
+    response_headers: Option<GatewayHeadersConfig>,
+    redirect: Option<GatewayRedirectConfig>,
+    respond: Option<GatewayRespondConfig>,
+    static_files: Option<GatewayStaticFilesConfig>,
+    compression: Option<GatewayCompressionConfig>,
+    cors: Option<GatewayCorsConfig>,
+    cache: Option<GatewayCacheConfig>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -77,8 +206,13 @@ struct GatewayLbMatchRule {
 
 #[derive(Deserialize, Serialize)]
 struct GatewayRewrite {
-    regex: String,
-    replacement: String,
+    regex: Option<String>,
+    replacement: Option<String>,
+    host: Option<String>,
+    preserve_host: Option<bool>,
+    scheme: Option<String>,
+    path_prefix: Option<String>,
+    query: Option<GatewayQueryRewriteConfig>,
 }
 
 impl Into<LbInfo> for GatewayLbRequest {
@@ -92,9 +226,33 @@ impl Into<LbInfo> for GatewayLbRequest {
             rewrite: self.rewrite.map(|r| LbRewriteInfo {
                 regex: r.regex,
                 replacement: r.replacement,
+                host: r.host,
+                preserve_host: r.preserve_host,
+                scheme: r.scheme,
+                path_prefix: r.path_prefix,
+                query: r.query,
             }),
             service_discovery: self.service_discovery,
             upstream: self.static_upstream,
+            protocol: self.protocol,
+            listen: self.listen,
+            proxy_protocol: self.proxy_protocol,
+            upstream_proxy_protocol: self.upstream_proxy_protocol,
+            streaming: self.streaming,
+            grpc: self.grpc,
+            rate_limit: self.rate_limit,
+            jwt: self.jwt,
+            require_application: self.require_application,
+            forward_auth: self.forward_auth,
+            ip_access: self.ip_access,
+            request_headers: self.request_headers,
+            response_headers: self.response_headers,
+            redirect: self.redirect,
+            respond: self.respond,
+            static_files: self.static_files,
+            compression: self.compression,
+            cors: self.cors,
+            cache: self.cache,
         }
     }
 }
@@ -108,7 +266,9 @@ async fn add_lb(Json(req): Json<GatewayLbRequest>) -> &'static str {
         }
     }
 
-    app::add_load_balancer(req.into()).await;
+    if !app::add_load_balancer(req.into()).await {
+        return "Invalid lb config";
+    }
 
     "LB added"
 }
@@ -132,9 +292,150 @@ async fn get_application(Json(app): Json<ApplicationRequest>) -> &'static str {
     "Application retrieved"
 }
 
+async fn get_quota_usage(
+    Json(req): Json<ApplicationIdRequest>,
+) -> Result<Json<QuotaUsageResponse>, (StatusCode, &'static str)> {
+    let apps = store::applications().read().await;
+    let app = apps
+        .get(&req.app_id)
+        .ok_or((StatusCode::NOT_FOUND, "Application not found"))?;
+    let quota = app
+        .quota()
+        .ok_or((StatusCode::NOT_FOUND, "Application has no quota"))?;
+
+    Ok(Json(QuotaUsageResponse {
+        usage: store::quotas().usage(&req.app_id, quota),
+        app_id: req.app_id,
+    }))
+}
+
+async fn reset_quota(Json(req): Json<ApplicationIdRequest>) -> &'static str {
+    store::quotas().reset(&req.app_id);
+    info!("Quota reset for application: {}", req.app_id);
+
+    "Quota reset"
+}
+
+async fn issue_api_key(
+    Json(req): Json<ApplicationIdRequest>,
+) -> Result<Json<ApiKeyResponse>, (StatusCode, &'static str)> {
+    let apps = store::applications().read().await;
+    let app = apps
+        .get(&req.app_id)
+        .ok_or((StatusCode::NOT_FOUND, "Application not found"))?;
+    let issued = app.credentials().issue();
+    if let Err(e) = store::issued_api_keys().add(&req.app_id, &issued.hash) {
+        error!(
+            "Failed to persist API key of application {}: {:?}",
+            req.app_id, e
+        );
+        app.credentials().revoke(&issued.id);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to persist API key",
+        ));
+    }
+    info!(
+        "API key {} issued for application: {}",
+        issued.id, req.app_id
+    );
+
+    Ok(Json(ApiKeyResponse {
+        app_id: req.app_id,
+        key_id: issued.id,
+        api_key: issued.key,
+        hash: issued.hash,
+    }))
+}
+
+async fn list_api_keys(
+    Json(req): Json<ApplicationIdRequest>,
+) -> Result<Json<ApiKeyListResponse>, (StatusCode, &'static str)> {
+    let apps = store::applications().read().await;
+    let app = apps
+        .get(&req.app_id)
+        .ok_or((StatusCode::NOT_FOUND, "Application not found"))?;
+
+    Ok(Json(ApiKeyListResponse {
+        key_ids: app.credentials().key_ids(),
+        app_id: req.app_id,
+    }))
+}
+
+async fn revoke_api_key(Json(req): Json<ApiKeyRequest>) -> (StatusCode, &'static str) {
+    let apps = store::applications().read().await;
+    let Some(app) = apps.get(&req.app_id) else {
+        return (StatusCode::NOT_FOUND, "Application not found");
+    };
+    if !app.credentials().revoke(&req.key_id) {
+        return (StatusCode::NOT_FOUND, "API key not found");
+    }
+    if let Err(e) = store::issued_api_keys().revoke(&req.app_id, &req.key_id) {
+        error!(
+            "Failed to persist revoked API key of application {}: {:?}",
+            req.app_id, e
+        );
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to persist API key",
+        );
+    }
+    info!(
+        "API key {} revoked for application: {}",
+        req.key_id, req.app_id
+    );
+
+    (StatusCode::OK, "API key revoked")
+}
+
+async fn get_ip_access(
+    Json(req): Json<IpAccessRequest>,
+) -> Result<Json<GatewayIpAccessConfig>, (StatusCode, &'static str)> {
+    let list = store::ip_access().get(&req.scope()?).unwrap_or_default();
+
+    Ok(Json(list.to_config()))
+}
+
+async fn update_ip_access(Json(req): Json<IpAccessRequest>) -> (StatusCode, &'static str) {
+    let scope = match req.scope() {
+        Ok(scope) => scope,
+        Err(e) => return e,
+    };
+    if !app::set_ip_access(scope, &req.ip_access) {
+        return (StatusCode::BAD_REQUEST, "Invalid cidr");
+    }
+
+    (StatusCode::OK, "Ip access updated")
+}
+
+async fn purge_cache(
+    Json(req): Json<CachePurgeRequest>,
+) -> Result<Json<CachePurgeResponse>, (StatusCode, &'static str)> {
+    let route = req.route.as_deref();
+    let purged = match (&req.key, &req.prefix) {
+        (Some(key), None) => cache::purge(route, |k| k == key),
+        (None, Some(prefix)) => cache::purge(route, |k| k.starts_with(prefix.as_str())),
+        _ => return Err((StatusCode::BAD_REQUEST, "Either key or prefix is required")),
+    };
+    info!(
+        "Purged {} cached responses of route {}",
+        purged,
+        route.unwrap_or("*")
+    );
+
+    Ok(Json(CachePurgeResponse { purged }))
+}
+
 async fn remove_application(Json(app): Json<ApplicationRequest>) -> &'static str {
     let app_name = app.app_id;
     store::applications().write().await.remove(&app_name);
+    store::ip_access().remove(&IpAccessScope::Application(app_name.clone()));
+    if let Err(e) = store::issued_api_keys().remove(&app_name) {
+        error!(
+            "Failed to remove API keys of application {}: {:?}",
+            app_name, e
+        );
+    }
 
     "Application removed"
 }
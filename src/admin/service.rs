@@ -33,7 +33,8 @@ impl Service for AdminService {
                 .with_rewrite(regex::Regex::new("^/admin").unwrap(), "".to_string());
 
                 if let Err(e) =
-                    crate::store::proxy_cmd(ProxyCmd::Add("admin".to_string(), options)).await
+                    crate::store::proxy_cmd(ProxyCmd::Add("admin".to_string(), Box::new(options)))
+                        .await
                 {
                     error!("err: {:?}", e);
                 }
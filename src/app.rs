@@ -1,16 +1,45 @@
 use std::{sync::Arc, time::Duration};
 
-use pingora_limits::rate::Rate;
+use ipnet::IpNet;
 use tracing::{error, info};
 
 use crate::{
-    config::{GatewayApplicationConfig, GatewayLoadBalancerConfig},
+    acl::{IpAccessList, IpAccessScope, RouteAcl},
+    action::RouteAction,
+    cache::RouteCache,
+    compression::Compression,
+    config::{
+        GatewayApplicationConfig, GatewayCacheConfig, GatewayCompressionConfig, GatewayCorsConfig,
+        GatewayCredentialsConfig, GatewayForwardAuthConfig, GatewayGrpcConfig,
+        GatewayHeadersConfig, GatewayIpAccessConfig, GatewayJwtConfig, GatewayListenerConfig,
+        GatewayLoadBalancerConfig, GatewayProxyProtocolConfig, GatewayQueryRewriteConfig,
+        GatewayQuotaConfig, GatewayRateLimitConfig, GatewayRedirectConfig, GatewayRespondConfig,
+        GatewayRouteAccessConfig, GatewayStaticFilesConfig, GatewayStreamingConfig,
+    },
+    cors::CorsPolicy,
+    credentials::{Credentials, HmacVerifier},
     docker::{background::DockerBackgroundService, servicediscovery::DockerServiceDiscovery},
-    lb::{GatewayLoadBalancerOptions, GatewayMatchRule, PingoraServiceDiscovery},
+    forward_auth::ForwardAuth,
+    headers::HeaderRules,
+    jwt::JwtPolicy,
+    lb::{
+        static_discovery, GatewayGrpc, GatewayLoadBalancerOptions, GatewayMatchRule,
+        GatewayStreaming, PingoraServiceDiscovery,
+    },
+    mtls::ClientCertBinding,
     proxy::ProxyCmd,
-    r#const::DOCKER_BACKGROUND_SERVICE_NAME,
-    rate_limit::RateLimiter,
+    proxy_protocol::{ProxyProtocolAcceptor, ProxyProtocolListener, ProxyProtocolVersion},
+    quota::{Quota, QuotaPeriod},
+    r#const::{
+        DOCKER_BACKGROUND_SERVICE_NAME, GATEWAY_INTERNAL_LISTEN, QUOTA_STORE_SERVICE_NAME,
+        UNIX_SOCKET_PREFIX,
+    },
+    rate_limit::{
+        ConcurrencyLimit, RateLimitAlgorithm, RateLimitKey, RateLimitPolicy, RateLimiter,
+    },
+    rewrite::{QueryRewrite, UpstreamRewrite},
     service::GlobalBackgroundCmd,
+    static_files::StaticFiles,
     store::{self, docker_client, GatewayApplication},
 };
 
@@ -18,6 +47,15 @@ pub struct Application {
     pub app_id: String,
     pub limit_interval_seconds: u64,
     pub limit: u32,
+    pub algorithm: Option<String>,
+    pub burst: Option<u32>,
+    pub distributed: Option<bool>,
+    pub quota: Option<GatewayQuotaConfig>,
+    pub max_concurrent: Option<u32>,
+    pub max_concurrent_queue_ms: Option<u64>,
+    pub credentials: Option<GatewayCredentialsConfig>,
+    pub routes: Option<Vec<GatewayRouteAccessConfig>>,
+    pub ip_access: Option<GatewayIpAccessConfig>,
 }
 
 impl From<&GatewayApplicationConfig> for Application {
@@ -26,29 +64,146 @@ impl From<&GatewayApplicationConfig> for Application {
             app_id: config.app_id.to_string(),
             limit_interval_seconds: config.limit_interval_seconds,
             limit: config.limit,
+            algorithm: config.algorithm.clone(),
+            burst: config.burst,
+            distributed: config.distributed,
+            quota: config.quota.clone(),
+            max_concurrent: config.max_concurrent,
+            max_concurrent_queue_ms: config.max_concurrent_queue_ms,
+            credentials: config.credentials.clone(),
+            routes: config.routes.clone(),
+            ip_access: config.ip_access.clone(),
         }
     }
 }
 
 impl Into<GatewayApplication> for Application {
     fn into(self) -> GatewayApplication {
-        GatewayApplication::new(RateLimiter::new(
-            Rate::new(Duration::from_secs(self.limit_interval_seconds)),
+        let rate_limiter = rate_limiter(
+            self.limit_interval_seconds,
             self.limit,
-        ))
+            self.algorithm.as_deref(),
+            self.burst,
+        );
+        let mut application = GatewayApplication::new(match store::redis_rate_limit() {
+            Some(redis) if self.distributed.unwrap_or(false) => rate_limiter.with_redis(redis),
+            _ => rate_limiter,
+        });
+        let quota = self.quota.and_then(|quota| {
+            QuotaPeriod::parse(&quota.period).map(|period| Quota {
+                limit: quota.limit,
+                period,
+            })
+        });
+        if let Some(quota) = quota {
+            application = application.with_quota(quota);
+        }
+        if let Some(max_concurrent) = self.max_concurrent {
+            let queue_timeout = Duration::from_millis(self.max_concurrent_queue_ms.unwrap_or(0));
+            application =
+                application.with_concurrency(ConcurrencyLimit::new(max_concurrent, queue_timeout));
+        }
+        // keys issued by the admin api outlive updates and restarts
+        let issued = store::issued_api_keys().hashes(&self.app_id);
+        if let Some(credentials) = self.credentials {
+            let hmac = credentials.hmac_secret.map(|secret| {
+                let max_skew = credentials.hmac_max_skew_seconds.unwrap_or(300);
+                let nonces = store::hmac_nonces(&self.app_id);
+                HmacVerifier::new(&secret, Duration::from_secs(max_skew), nonces)
+            });
+            let mut verifier = Credentials::new(credentials.api_keys.as_deref(), hmac);
+            // the binding was validated when the application was added
+            if let Some(binding) = credentials
+                .client_cert
+                .as_ref()
+                .and_then(|client_cert| ClientCertBinding::new(client_cert).ok())
+            {
+                verifier = verifier.with_client_cert(binding);
+            }
+            if !issued.is_empty() {
+                verifier = verifier.with_api_keys(&issued);
+            }
+            application = application.with_credentials(verifier);
+        } else if !issued.is_empty() {
+            application =
+                application.with_credentials(Credentials::default().with_api_keys(&issued));
+        }
+        if let Some(routes) = &self.routes {
+            application = application.with_acl(RouteAcl::new(routes));
+        }
+        application
     }
 }
 
 pub async fn add_application(app: Application) {
     let app_name = app.app_id.clone();
+    if let Some(algorithm) = &app.algorithm
+        && RateLimitAlgorithm::parse(algorithm).is_none()
+    {
+        error!(
+            "Unknown rate limit algorithm {} for application {}",
+            algorithm, app_name
+        );
+        return;
+    }
+    if let Some(quota) = &app.quota {
+        if QuotaPeriod::parse(&quota.period).is_none() {
+            error!(
+                "Unknown quota period {} for application {}",
+                quota.period, app_name
+            );
+            return;
+        }
+        // counters are persisted in the background once any quota exists
+        let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Add(
+            QUOTA_STORE_SERVICE_NAME.to_string(),
+            Box::new(store::quotas()),
+        ))
+        .await;
+    }
+    if app.distributed.unwrap_or(false) && store::redis_rate_limit().is_none() {
+        error!(
+            "Application {} is distributed but redis is not configured, limiting locally",
+            app_name
+        );
+    }
+    if let Some(client_cert) = app
+        .credentials
+        .as_ref()
+        .and_then(|credentials| credentials.client_cert.as_ref())
+        && let Err(e) = ClientCertBinding::new(client_cert)
+    {
+        error!(
+            "Invalid client certificate of application {}: {}",
+            app_name, e
+        );
+        return;
+    }
+    let ip_access_scope = IpAccessScope::Application(app_name.clone());
+    let Some(ip_access) = parse_ip_access(&ip_access_scope, app.ip_access.as_ref()) else {
+        return;
+    };
     info!(
         "Application: {}, Max Requests per Second: {}, Limit: {}",
         app_name, app.limit_interval_seconds, app.limit
     );
+    apply_ip_access(ip_access_scope, ip_access);
+    let mut application: GatewayApplication = app.into();
+    // requests in flight keep the slots they hold on the previous limit
+    let resized = match store::applications().read().await.get(&app_name) {
+        Some(previous) => previous
+            .concurrency()
+            .zip(application.concurrency())
+            .map(|(previous, concurrency)| previous.resized(concurrency)),
+        None => None,
+    };
+    if let Some(concurrency) = resized {
+        application = application.with_concurrency(concurrency);
+    }
     store::applications()
         .write()
         .await
-        .insert(app_name, Arc::new(app.into()));
+        .insert(app_name, Arc::new(application));
 }
 
 pub struct LbInfo {
@@ -57,6 +212,25 @@ pub struct LbInfo {
     pub rewrite: Option<LbRewriteInfo>,
     pub service_discovery: String,
     pub upstream: Option<Vec<String>>,
+    pub protocol: Option<String>,
+    pub listen: Option<String>,
+    pub proxy_protocol: Option<GatewayProxyProtocolConfig>,
+    pub upstream_proxy_protocol: Option<String>,
+    pub streaming: Option<GatewayStreamingConfig>,
+    pub grpc: Option<GatewayGrpcConfig>,
+    pub rate_limit: Option<GatewayRateLimitConfig>,
+    pub jwt: Option<GatewayJwtConfig>,
+    pub require_application: Option<bool>,
+    pub forward_auth: Option<GatewayForwardAuthConfig>,
+    pub ip_access: Option<GatewayIpAccessConfig>,
+    pub request_headers: Option<GatewayHeadersConfig>,
+    pub response_headers: Option<GatewayHeadersConfig>,
+    pub redirect: Option<GatewayRedirectConfig>,
+    pub respond: Option<GatewayRespondConfig>,
+    pub static_files: Option<GatewayStaticFilesConfig>,
+    pub compression: Option<GatewayCompressionConfig>,
+    pub cors: Option<GatewayCorsConfig>,
+    pub cache: Option<GatewayCacheConfig>,
 }
 
 pub struct LbMatchRuleInfo {
@@ -65,8 +239,13 @@ pub struct LbMatchRuleInfo {
 }
 
 pub struct LbRewriteInfo {
-    pub regex: String,
-    pub replacement: String,
+    pub regex: Option<String>,
+    pub replacement: Option<String>,
+    pub host: Option<String>,
+    pub preserve_host: Option<bool>,
+    pub scheme: Option<String>,
+    pub path_prefix: Option<String>,
+    pub query: Option<GatewayQueryRewriteConfig>,
 }
 
 impl From<&GatewayLoadBalancerConfig> for LbInfo {
@@ -80,45 +259,134 @@ impl From<&GatewayLoadBalancerConfig> for LbInfo {
             rewrite: config.rewrite.as_ref().map(|r| LbRewriteInfo {
                 regex: r.regex.clone(),
                 replacement: r.replacement.clone(),
+                host: r.host.clone(),
+                preserve_host: r.preserve_host,
+                scheme: r.scheme.clone(),
+                path_prefix: r.path_prefix.clone(),
+                query: r.query.clone(),
             }),
             service_discovery: config.service_discovery.clone(),
             upstream: config.upstream.clone(),
+            protocol: config.protocol.clone(),
+            listen: config.listen.clone(),
+            proxy_protocol: config.proxy_protocol.clone(),
+            upstream_proxy_protocol: config.upstream_proxy_protocol.clone(),
+            streaming: config.streaming.clone(),
+            grpc: config.grpc.clone(),
+            rate_limit: config.rate_limit.clone(),
+            jwt: config.jwt.clone(),
+            require_application: config.require_application,
+            forward_auth: config.forward_auth.clone(),
+            ip_access: config.ip_access.clone(),
+            request_headers: config.request_headers.clone(),
+            response_headers: config.response_headers.clone(),
+            redirect: config.redirect.clone(),
+            respond: config.respond.clone(),
+            static_files: config.static_files.clone(),
+            compression: config.compression.clone(),
+            cors: config.cors.clone(),
+            cache: config.cache.clone(),
         }
     }
 }
 
-pub async fn add_load_balancer(lb: LbInfo) {
+/// Add or replace a route, false when its config is rejected
+pub async fn add_load_balancer(lb: LbInfo) -> bool {
     let match_rule = match lb.match_rule.typ.as_str() {
         "path_start_with" => GatewayMatchRule::PathStartsWith(lb.match_rule.value.to_string()),
         "path_regex" => {
             let regex = regex::Regex::new(&lb.match_rule.value).unwrap();
             GatewayMatchRule::PathStartsWith(regex.to_string())
         }
-        _ => return,
+        "grpc_service" => {
+            GatewayMatchRule::GrpcMethod(lb.match_rule.value.trim_matches('/').to_string())
+        }
+        "sni" => GatewayMatchRule::Sni(lb.match_rule.value.to_ascii_lowercase()),
+        "any" => GatewayMatchRule::Any,
+        _ => return false,
     };
 
-    let rewrite = if let Some(rewrite) = &lb.rewrite {
-        let regex = regex::Regex::new(&rewrite.regex).unwrap();
-        Some((regex, rewrite.replacement.clone()))
-    } else {
-        None
+    let rewrite = match lb.rewrite.as_ref().map(|r| (&r.regex, &r.replacement)) {
+        Some((Some(regex), Some(replacement))) => match regex::Regex::new(regex) {
+            Ok(regex) => Some((regex, replacement.clone())),
+            Err(e) => {
+                error!("Invalid rewrite regex for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        },
+        Some((None, None)) | None => None,
+        Some(_) => {
+            error!(
+                "Rewrite of route {} needs both a regex and a replacement",
+                lb.name
+            );
+            return false;
+        }
+    };
+    let upstream_rewrite = match &lb.rewrite {
+        Some(rewrite) => {
+            let tls = match rewrite.scheme.as_deref().unwrap_or("http") {
+                "http" => false,
+                "https" => true,
+                scheme => {
+                    error!("Unknown upstream scheme {} for route {}", scheme, lb.name);
+                    return false;
+                }
+            };
+            let query = rewrite.query.as_ref().map(QueryRewrite::new);
+            match UpstreamRewrite::new(
+                rewrite.host.clone(),
+                rewrite.preserve_host.unwrap_or(true),
+                tls,
+                rewrite.path_prefix.clone(),
+                query.unwrap_or_default(),
+            ) {
+                Ok(upstream_rewrite) => Some(upstream_rewrite),
+                Err(e) => {
+                    error!("Invalid upstream rewrite for route {}: {:?}", lb.name, e);
+                    return false;
+                }
+            }
+        }
+        None => None,
     };
 
-    let (service_discovery, default_health_check): (PingoraServiceDiscovery, bool) = match lb
-        .service_discovery
-        .as_str()
-    {
-        "static" => (
-            pingora::lb::discovery::Static::try_from_iter(lb.upstream.clone().unwrap_or_default())
-                .unwrap(),
-            false,
-        ),
-        "docker" => (
-            Box::new(DockerServiceDiscovery::new(&lb.name, docker_client())),
-            true,
-        ),
-        _ => return,
+    let action = match (&lb.redirect, &lb.respond, &lb.static_files) {
+        (Some(redirect), None, None) => Some(RouteAction::redirect(redirect)),
+        (None, Some(respond), None) => Some(RouteAction::respond(respond)),
+        (None, None, Some(static_files)) => {
+            Some(StaticFiles::new(static_files).map(RouteAction::StaticFiles))
+        }
+        (None, None, None) => None,
+        _ => Some(Err(anyhow::anyhow!(
+            "only one of redirect, respond and static_files may be set"
+        ))),
     };
+    let action = match action.transpose() {
+        Ok(action) => action,
+        Err(e) => {
+            error!("Invalid action for route {}: {:?}", lb.name, e);
+            return false;
+        }
+    };
+
+    let (service_discovery, default_health_check): (PingoraServiceDiscovery, bool) =
+        match lb.service_discovery.as_str() {
+            // answered by the gateway, there is nothing to discover
+            _ if action.is_some() => (static_discovery(&[]).unwrap(), false),
+            "static" => match static_discovery(lb.upstream.as_deref().unwrap_or_default()) {
+                Ok(discovery) => (discovery, false),
+                Err(e) => {
+                    error!("Invalid upstream for route {}: {:?}", lb.name, e);
+                    return false;
+                }
+            },
+            "docker" => (
+                Box::new(DockerServiceDiscovery::new(&lb.name, docker_client())),
+                true,
+            ),
+            _ => return false,
+        };
 
     let mut options =
         GatewayLoadBalancerOptions::new(match_rule, service_discovery, default_health_check);
@@ -126,10 +394,268 @@ pub async fn add_load_balancer(lb: LbInfo) {
     if let Some((regex, replacement)) = rewrite {
         options = options.with_rewrite(regex, replacement);
     }
+    if let Some(upstream_rewrite) = upstream_rewrite {
+        options = options.with_upstream_rewrite(upstream_rewrite);
+    }
+    if let Some(action) = action {
+        options = options.with_action(action);
+    }
+
+    match lb.protocol.as_deref().unwrap_or("http") {
+        "http" => {}
+        "grpc" => {
+            let grpc = lb.grpc.as_ref();
+            if let Some(health_check) = grpc.and_then(|g| g.health_check) {
+                options = options.with_health_check(health_check);
+            }
+            options = options.with_grpc(GatewayGrpc {
+                health_service: grpc
+                    .and_then(|g| g.health_service.clone())
+                    .unwrap_or_default(),
+            });
+        }
+        "tcp" => match &lb.listen {
+            Some(listen) => options = options.with_listen(listen.clone()),
+            None => {
+                error!("tcp route {} requires a listen address", lb.name);
+                return false;
+            }
+        },
+        protocol => {
+            error!("Unknown protocol {} for route {}", protocol, lb.name);
+            return false;
+        }
+    }
+
+    let proxy_protocol = match lb
+        .proxy_protocol
+        .as_ref()
+        .map(|p| parse_cidrs(&p.trusted_cidrs))
+    {
+        Some(Ok(cidrs)) => Some(ProxyProtocolAcceptor::new(cidrs)),
+        Some(Err(e)) => {
+            error!("Invalid trusted cidrs for route {}: {:?}", lb.name, e);
+            return false;
+        }
+        None => None,
+    };
+    // all tcp routes on a listener share its proxy protocol settings
+    if let Some(listen) = options.listen.as_deref() {
+        let routes = crate::store::stream_routes().read().await;
+        if let Some(other) = routes.values().find(|other| {
+            other.name() != lb.name
+                && other.listen() == Some(listen)
+                && other.proxy_protocol().as_deref() != proxy_protocol.as_ref()
+        }) {
+            error!(
+                "Route {} conflicts with route {} on listener {}: proxy_protocol differs",
+                lb.name,
+                other.name(),
+                listen
+            );
+            return false;
+        }
+        // the first route of an address starts its listener
+        let listening = routes.values().any(|other| other.listen() == Some(listen));
+        drop(routes);
+        if !listening && let Err(e) = tokio::net::TcpListener::bind(listen).await {
+            error!(
+                "Failed to bind listener {} of route {}: {:?}",
+                listen, lb.name, e
+            );
+            return false;
+        }
+    }
+    if let Some(acceptor) = proxy_protocol {
+        options = options.with_proxy_protocol(acceptor);
+    }
+
+    if let Some(version) = &lb.upstream_proxy_protocol {
+        match ProxyProtocolVersion::parse(version) {
+            Some(version) => options = options.with_upstream_proxy_protocol(version),
+            None => {
+                error!(
+                    "Unknown proxy protocol version {} for route {}",
+                    version, lb.name
+                );
+                return false;
+            }
+        }
+    }
+
+    if let Some(streaming) = &lb.streaming {
+        options = options.with_streaming(GatewayStreaming {
+            idle_timeout: streaming.idle_timeout_seconds.map(Duration::from_secs),
+            stream_idle_timeout: streaming
+                .stream_idle_timeout_seconds
+                .map(Duration::from_secs),
+        });
+    }
+
+    if let Some(rate_limit) = &lb.rate_limit {
+        info!(
+            "Route: {}, Limit Interval Seconds: {}, Limit: {}",
+            lb.name, rate_limit.limit_interval_seconds, rate_limit.limit
+        );
+        match rate_limit_policy(rate_limit, RateLimitKey::default()) {
+            // claims are only known once the route's jwt policy verified them
+            Ok(policy) if policy.key.uses_claims() && lb.jwt.is_none() => {
+                error!(
+                    "Rate limit key of route {} uses claims without a jwt policy",
+                    lb.name
+                );
+                return false;
+            }
+            Ok(policy) => options = options.with_rate_limit(policy),
+            Err(e) => {
+                error!("Invalid rate limit for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    if lb.require_application.unwrap_or(false) {
+        options = options.with_require_application(true);
+    }
+
+    if let Some(jwt) = &lb.jwt {
+        match JwtPolicy::new(jwt) {
+            Ok(policy) => options = options.with_jwt(policy),
+            Err(e) => {
+                error!("Invalid jwt policy for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    let ip_access_scope = IpAccessScope::Route(lb.name.clone());
+    let Some(ip_access) = parse_ip_access(&ip_access_scope, lb.ip_access.as_ref()) else {
+        return false;
+    };
+
+    if let Some(forward_auth) = &lb.forward_auth {
+        match ForwardAuth::new(forward_auth) {
+            Ok(forward_auth) => options = options.with_forward_auth(forward_auth),
+            Err(e) => {
+                error!("Invalid forward auth for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
 
-    if let Err(e) = crate::store::proxy_cmd(ProxyCmd::Add(lb.name.to_string(), options)).await {
+    if let Some(request_headers) = &lb.request_headers {
+        match HeaderRules::new(request_headers) {
+            Ok(rules) => options = options.with_request_headers(rules),
+            Err(e) => {
+                error!("Invalid request headers for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(response_headers) = &lb.response_headers {
+        match HeaderRules::new(response_headers) {
+            Ok(rules) => options = options.with_response_headers(rules),
+            Err(e) => {
+                error!("Invalid response headers for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(compression) = &lb.compression {
+        match Compression::new(compression) {
+            Ok(compression) => options = options.with_compression(compression),
+            Err(e) => {
+                error!("Invalid compression for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(cors) = &lb.cors {
+        match CorsPolicy::new(cors) {
+            Ok(cors) => options = options.with_cors(cors),
+            Err(e) => {
+                error!("Invalid cors for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    if let Some(cache) = &lb.cache {
+        match crate::cache::storage(cache.storage.as_deref()).and_then(|storage| {
+            RouteCache::new(cache, storage, lb.require_application.unwrap_or(false))
+        }) {
+            Ok(cache) => options = options.with_cache(cache),
+            Err(e) => {
+                error!("Invalid cache for route {}: {:?}", lb.name, e);
+                return false;
+            }
+        }
+    }
+
+    // only a valid route replaces the list, an omitted one clears it
+    apply_ip_access(ip_access_scope, ip_access);
+    if let Err(e) =
+        crate::store::proxy_cmd(ProxyCmd::Add(lb.name.to_string(), Box::new(options))).await
+    {
         error!("err: {:?}", e);
+        return false;
+    }
+    true
+}
+
+fn rate_limiter(
+    limit_interval_seconds: u64,
+    limit: u32,
+    algorithm: Option<&str>,
+    burst: Option<u32>,
+) -> RateLimiter {
+    let interval = Duration::from_secs(limit_interval_seconds);
+    match algorithm.and_then(RateLimitAlgorithm::parse) {
+        Some(RateLimitAlgorithm::TokenBucket) => {
+            RateLimiter::token_bucket(limit, burst.unwrap_or(limit), interval)
+        }
+        _ => RateLimiter::new(interval, limit),
+    }
+}
+
+/// Build the default rate limit, keyed by client ip unless set otherwise
+pub fn default_rate_limit_policy(
+    config: &GatewayRateLimitConfig,
+) -> anyhow::Result<RateLimitPolicy> {
+    let policy = rate_limit_policy(config, RateLimitKey::client_ip())?;
+    if policy.key.uses_claims() {
+        anyhow::bail!("claim keys need the jwt policy of a route");
+    }
+    Ok(policy)
+}
+
+/// Build a route or default rate limit, `default_key` applies when the
+/// config sets no key
+pub fn rate_limit_policy(
+    config: &GatewayRateLimitConfig,
+    default_key: RateLimitKey,
+) -> anyhow::Result<RateLimitPolicy> {
+    if let Some(algorithm) = &config.algorithm
+        && RateLimitAlgorithm::parse(algorithm).is_none()
+    {
+        anyhow::bail!("unknown rate limit algorithm {}", algorithm);
     }
+    let key = match &config.key {
+        Some(key) => RateLimitKey::parse(key)?,
+        None => default_key,
+    };
+    Ok(RateLimitPolicy {
+        key,
+        limiter: rate_limiter(
+            config.limit_interval_seconds,
+            config.limit,
+            config.algorithm.as_deref(),
+            config.burst,
+        ),
+    })
 }
 
 pub struct BackgroundServer {
@@ -163,3 +689,78 @@ pub async fn start_background_service(server: BackgroundServer) {
     ))
     .await;
 }
+
+/// Replace the ip access list of a scope, false when the list is invalid
+pub fn set_ip_access(scope: IpAccessScope, config: &GatewayIpAccessConfig) -> bool {
+    match parse_ip_access(&scope, Some(config)) {
+        Some(list) => {
+            apply_ip_access(scope, list);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Parse the ip access list of a scope, an omitted list allows every client
+fn parse_ip_access(
+    scope: &IpAccessScope,
+    config: Option<&GatewayIpAccessConfig>,
+) -> Option<IpAccessList> {
+    match config.map(IpAccessList::parse).transpose() {
+        Ok(list) => Some(list.unwrap_or_default()),
+        Err(e) => {
+            error!("Invalid ip access list for {:?}: {:?}", scope, e);
+            None
+        }
+    }
+}
+
+fn apply_ip_access(scope: IpAccessScope, list: IpAccessList) {
+    info!("Ip access of {:?}: {:?}", scope, list);
+    store::ip_access().set(scope, list);
+}
+
+/// Parse CIDR ranges, a bare ip address is taken as a single host range
+pub fn parse_cidrs(cidrs: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .or_else(|_| cidr.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow::anyhow!("invalid cidr: {}", cidr))
+        })
+        .collect()
+}
+
+pub async fn start_proxy_protocol_listener(listener: &GatewayListenerConfig) {
+    let Some(proxy_protocol) = &listener.proxy_protocol else {
+        return;
+    };
+
+    if listener.address.starts_with(UNIX_SOCKET_PREFIX) {
+        error!(
+            "Proxy protocol is not supported on unix listener {}",
+            listener.address
+        );
+        return;
+    }
+
+    let cidrs = match parse_cidrs(&proxy_protocol.trusted_cidrs) {
+        Ok(cidrs) => cidrs,
+        Err(e) => {
+            error!("Invalid trusted cidrs for {}: {:?}", listener.address, e);
+            return;
+        }
+    };
+
+    info!("Starting proxy protocol listener: {}", listener.address);
+    let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Add(
+        format!("{}_listener", listener.address),
+        Box::new(Arc::new(ProxyProtocolListener::new(
+            &listener.address,
+            GATEWAY_INTERNAL_LISTEN,
+            ProxyProtocolAcceptor::new(cidrs),
+        ))),
+    ))
+    .await;
+}
@@ -0,0 +1,672 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    path::PathBuf,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use axum::http::{header, HeaderName};
+use bytes::Bytes;
+use pingora::{
+    cache::{
+        cache_control::{CacheControl, Cacheable, InterpretCacheControl},
+        eviction::{simple_lru, EvictionManager},
+        filters::{calculate_serve_stale_sec, resp_cacheable},
+        key::{CacheHashKey, CompactCacheKey, HashBinary},
+        lock::CacheKeyLockImpl,
+        storage::{HandleHit, HandleMiss, MissFinishType},
+        trace::SpanHandle,
+        CacheKey, CacheMeta, CacheMetaDefaults, HitHandler, HttpCache, MissHandler, NoCacheReason,
+        PurgeType, RespCacheable, Storage, VarianceBuilder,
+    },
+    http::{RequestHeader, ResponseHeader},
+    Error, ErrorType, Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::error;
+
+use crate::config::GatewayCacheConfig;
+
+/// The statuses cached for the default TTL, the ones RFC 9110 calls
+/// heuristically cacheable
+const DEFAULT_TTL_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+const DISK_READ_SIZE: usize = 64 * 1024;
+
+/// The response caching of a route
+pub struct RouteCache {
+    storage: &'static CacheStorage,
+    default_ttl: Option<u32>,
+    key_query: bool,
+    key_host: bool,
+    key_app_id: bool,
+    vary_headers: Vec<HeaderName>,
+    stale_while_revalidate: u32,
+    stale_if_error: u32,
+    collapse_misses: bool,
+    max_file_size: Option<usize>,
+}
+
+impl RouteCache {
+    /// Routes that require an application keep the responses of each one
+    /// apart unless `key_app_id` says otherwise
+    pub fn new(
+        config: &GatewayCacheConfig,
+        storage: &'static CacheStorage,
+        require_application: bool,
+    ) -> anyhow::Result<Self> {
+        let collapse_misses = config.collapse_misses.unwrap_or(true);
+        let stale_while_revalidate = config.stale_while_revalidate_seconds.unwrap_or(0);
+        // the stale response is only served while a collapsed miss updates it
+        if stale_while_revalidate > 0 && !collapse_misses {
+            anyhow::bail!("stale_while_revalidate_seconds needs collapse_misses");
+        }
+        let vary_headers = config
+            .vary_headers
+            .iter()
+            .flatten()
+            .map(|name| HeaderName::try_from(name.as_str()))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self {
+            storage,
+            default_ttl: config.default_ttl_seconds,
+            key_query: config.key_query.unwrap_or(true),
+            key_host: config.key_host.unwrap_or(true),
+            key_app_id: config.key_app_id.unwrap_or(require_application),
+            vary_headers,
+            stale_while_revalidate,
+            stale_if_error: config.stale_if_error_seconds.unwrap_or(0),
+            collapse_misses,
+            max_file_size: config.max_file_size,
+        })
+    }
+
+    /// Look the request up in the storage of the route
+    pub fn enable(&self, cache: &mut HttpCache) {
+        let storage = self.storage;
+        let lock: Option<&'static CacheKeyLockImpl> = match self.collapse_misses {
+            true => Some(crate::store::cache_lock()),
+            false => None,
+        };
+        cache.enable(storage, Some(&storage.eviction), None, lock);
+        if let Some(max_file_size) = self.max_file_size {
+            cache.set_max_file_size_bytes(max_file_size);
+        }
+    }
+
+    /// The key of a request, its path and query. Everything else the
+    /// response depends on tells its variants apart.
+    pub fn key(&self, route: &str, req: &RequestHeader) -> CacheKey {
+        let primary = match req.uri.query().filter(|_| self.key_query) {
+            Some(query) => format!("{}?{}", req.uri.path(), query),
+            None => req.uri.path().to_string(),
+        };
+        CacheKey::new(route, primary, "")
+    }
+
+    /// The variant of a cached response a request gets, by the Vary of the
+    /// response and the key options of the route
+    pub fn variance(
+        &self,
+        meta: &CacheMeta,
+        req: &RequestHeader,
+        app_id: Option<&str>,
+    ) -> Option<HashBinary> {
+        let names = vary(meta.response_header())
+            .chain(self.vary_headers.iter().map(|name| name.to_string()))
+            .collect::<Vec<_>>();
+        let mut variance = VarianceBuilder::new();
+        for name in &names {
+            let value = req.headers.get(name.as_str()).map(|v| v.as_bytes());
+            variance.add_value(name, value.unwrap_or_default());
+        }
+        if self.key_host {
+            // http2 requests carry the host in the uri instead
+            let host = req
+                .headers
+                .get(header::HOST)
+                .map(|v| v.as_bytes())
+                .or_else(|| req.uri.authority().map(|a| a.as_str().as_bytes()));
+            variance.add_value("host", host.unwrap_or_default());
+        }
+        if self.key_app_id {
+            variance.add_value("$app_id", app_id.unwrap_or_default());
+        }
+        variance.finalize()
+    }
+
+    /// Whether and how long a response is cached, by its Cache-Control or
+    /// Expires and else by the default TTL of the route. Responses to an
+    /// application are shared like those to an Authorization request unless
+    /// they are kept apart by app id.
+    pub fn cacheable(
+        &self,
+        req: &RequestHeader,
+        resp: &ResponseHeader,
+        app_id: Option<&str>,
+    ) -> RespCacheable {
+        // a response for one user, or one no request can be matched to
+        if resp.headers.contains_key(header::SET_COOKIE) || vary(resp).any(|name| name == "*") {
+            return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+        }
+        let cache_control = CacheControl::from_resp_headers(resp);
+        let authorization = req.headers.contains_key(header::AUTHORIZATION)
+            || (app_id.is_some() && !self.key_app_id);
+        let defaults =
+            CacheMetaDefaults::new(|_| None, self.stale_while_revalidate, self.stale_if_error);
+        let cacheable = resp_cacheable(
+            cache_control.as_ref(),
+            resp.clone(),
+            authorization,
+            &defaults,
+        );
+        let Some(ttl) = self.default_ttl else {
+            return cacheable;
+        };
+        if !matches!(cacheable, RespCacheable::Uncacheable(_))
+            || authorization
+            || cache_control
+                .as_ref()
+                .is_some_and(|cc| cc.is_cacheable() == Cacheable::No)
+            || !DEFAULT_TTL_STATUSES.contains(&resp.status.as_u16())
+        {
+            return cacheable;
+        }
+        let now = SystemTime::now();
+        let (stale_while_revalidate, stale_if_error) =
+            calculate_serve_stale_sec(cache_control.as_ref(), &defaults);
+        RespCacheable::Cacheable(CacheMeta::new(
+            now + Duration::from_secs(ttl.into()),
+            now,
+            stale_while_revalidate,
+            stale_if_error,
+            resp.clone(),
+        ))
+    }
+}
+
+/// The lowercase names of the Vary header
+fn vary(resp: &ResponseHeader) -> impl Iterator<Item = String> + '_ {
+    resp.headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+/// The storage a route caches in, "memory" (default) or "disk"
+pub fn storage(name: Option<&str>) -> anyhow::Result<&'static CacheStorage> {
+    match name.unwrap_or("memory") {
+        "memory" => Ok(crate::store::memory_cache()),
+        "disk" => crate::store::disk_cache().ok_or(anyhow::anyhow!("no disk cache configured")),
+        other => anyhow::bail!("unknown cache storage: {}", other),
+    }
+}
+
+/// Remove the cached responses whose key matches from every storage,
+/// returns how many were removed
+pub fn purge(route: Option<&str>, matches: impl Fn(&str) -> bool) -> usize {
+    std::iter::once(crate::store::memory_cache())
+        .chain(crate::store::disk_cache())
+        .map(|storage| storage.purge_matching(route, &matches))
+        .sum()
+}
+
+/// A cached response. On disk it is written as "<hash>.entry" and its
+/// body as "<hash>.body".
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    route: String,
+    primary: String,
+    key: CompactCacheKey,
+    meta: (Vec<u8>, Vec<u8>),
+    size: usize,
+    /// Only kept here by the memory storage
+    #[serde(skip)]
+    body: Bytes,
+}
+
+/// The cached responses of all routes in memory or on disk, the least
+/// recently used ones are evicted beyond the size limit
+pub struct CacheStorage {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    /// `None` keeps the bodies in memory
+    dir: Option<PathBuf>,
+    eviction: simple_lru::Manager,
+}
+
+impl CacheStorage {
+    pub fn memory(max_size: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            dir: None,
+            eviction: simple_lru::Manager::new(max_size),
+        }
+    }
+
+    /// The storage of the directory, with the responses it already holds
+    pub fn disk(path: &str, max_size: usize) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let storage = Self {
+            entries: RwLock::new(HashMap::new()),
+            dir: Some(PathBuf::from(path)),
+            eviction: simple_lru::Manager::new(max_size),
+        };
+        let mut bodies = Vec::new();
+        for file in std::fs::read_dir(path)? {
+            let path = file?.path();
+            let Some(hash) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("entry") => {}
+                Some("body") => {
+                    bodies.push((hash.to_string(), path));
+                    continue;
+                }
+                // left by writes the last run did not finish
+                Some("tmp") => {
+                    let _ = std::fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let entry = std::fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<CacheEntry>(&data).ok());
+            let meta = entry
+                .as_ref()
+                .and_then(|entry| CacheMeta::deserialize(&entry.meta.0, &entry.meta.1).ok());
+            let (Some(entry), Some(meta)) = (entry, meta) else {
+                error!("Invalid cache entry {}", path.display());
+                let _ = std::fs::remove_file(&path);
+                continue;
+            };
+            let evicted = storage
+                .eviction
+                .admit(entry.key.clone(), entry.size, meta.fresh_until());
+            storage
+                .entries
+                .write()
+                .unwrap()
+                .insert(hash.to_string(), entry);
+            for key in evicted {
+                storage.remove(&key.combined());
+            }
+        }
+        // bodies of entries that were never written or were evicted
+        for (hash, path) in bodies {
+            if !storage.entries.read().unwrap().contains_key(&hash) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(storage)
+    }
+
+    fn path(&self, hash: &str, extension: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(format!("{}.{}", hash, extension)))
+    }
+
+    async fn write_entry(&self, hash: &str, entry: &CacheEntry) -> Result<()> {
+        let Some(path) = self.path(hash, "entry") else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec(entry)
+            .map_err(|e| Error::because(ErrorType::InternalError, "invalid cache entry", e))?;
+        // replace the file atomically so a crash never leaves it truncated
+        let tmp = path.with_extension(format!("{}.tmp", rand::random::<u32>()));
+        tokio::fs::write(&tmp, data).await.map_err(io_error)?;
+        tokio::fs::rename(&tmp, &path).await.map_err(io_error)
+    }
+
+    fn remove(&self, hash: &str) -> bool {
+        let Some(entry) = self.entries.write().unwrap().remove(hash) else {
+            return false;
+        };
+        self.eviction.remove(&entry.key);
+        for extension in ["entry", "body"] {
+            if let Some(path) = self.path(hash, extension) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        true
+    }
+
+    /// Remove the responses of a route, or of every route, whose key
+    /// matches, returns how many were removed
+    pub fn purge_matching(&self, route: Option<&str>, matches: impl Fn(&str) -> bool) -> usize {
+        let hashes = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| route.is_none_or(|route| route == entry.route))
+            .filter(|(_, entry)| matches(&entry.primary))
+            .map(|(hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+        hashes.iter().filter(|hash| self.remove(hash)).count()
+    }
+}
+
+fn io_error(e: std::io::Error) -> Box<Error> {
+    Error::because(ErrorType::InternalError, "cache storage failed", e)
+}
+
+#[async_trait]
+impl Storage for CacheStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let hash = key.combined();
+        let Some(entry) = self.entries.read().unwrap().get(&hash).cloned() else {
+            return Ok(None);
+        };
+        let meta = CacheMeta::deserialize(&entry.meta.0, &entry.meta.1)?;
+        let hit = match self.path(&hash, "body") {
+            Some(path) => match File::open(&path).await {
+                Ok(file) => CacheHit::Disk(file),
+                Err(e) => {
+                    error!("Failed to open cached body {}: {:?}", path.display(), e);
+                    self.remove(&hash);
+                    return Ok(None);
+                }
+            },
+            None => CacheHit::Memory(Some(entry.body)),
+        };
+        Ok(Some((meta, Box::new(hit))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let hash = key.combined();
+        let extension = format!("{}.tmp", rand::random::<u32>());
+        let body = match self.path(&hash, &extension) {
+            Some(path) => {
+                CacheMissBody::Disk(File::create(&path).await.map_err(io_error)?, Some(path))
+            }
+            None => CacheMissBody::Memory(Vec::new()),
+        };
+        Ok(Box::new(CacheMiss {
+            storage: self,
+            hash,
+            entry: CacheEntry {
+                route: key.namespace().to_string(),
+                primary: key.primary_key().to_string(),
+                key: key.to_compact(),
+                meta: meta.serialize()?,
+                size: 0,
+                body: Bytes::new(),
+            },
+            body,
+        }))
+    }
+
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        Ok(self.remove(&key.combined()))
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let hash = key.combined();
+        let meta = meta.serialize()?;
+        let entry = match self.entries.write().unwrap().get_mut(&hash) {
+            Some(entry) => {
+                entry.meta = meta;
+                entry.clone()
+            }
+            None => return Ok(false),
+        };
+        self.write_entry(&hash, &entry).await?;
+        Ok(true)
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+enum CacheHit {
+    Memory(Option<Bytes>),
+    Disk(File),
+}
+
+#[async_trait]
+impl HandleHit for CacheHit {
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        match self {
+            CacheHit::Memory(body) => Ok(body.take()),
+            CacheHit::Disk(file) => {
+                let mut buf = vec![0; DISK_READ_SIZE];
+                let n = file.read(&mut buf).await.map_err(io_error)?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                buf.truncate(n);
+                Ok(Some(buf.into()))
+            }
+        }
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+enum CacheMissBody {
+    Memory(Vec<u8>),
+    /// The file is written under a temporary name until it is complete
+    Disk(File, Option<PathBuf>),
+}
+
+struct CacheMiss {
+    storage: &'static CacheStorage,
+    hash: String,
+    entry: CacheEntry,
+    body: CacheMissBody,
+}
+
+#[async_trait]
+impl HandleMiss for CacheMiss {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        self.entry.size += data.len();
+        match &mut self.body {
+            CacheMissBody::Memory(body) => body.extend_from_slice(&data),
+            CacheMissBody::Disk(file, _) => file.write_all(&data).await.map_err(io_error)?,
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<MissFinishType> {
+        let mut entry = self.entry.clone();
+        match &mut self.body {
+            CacheMissBody::Memory(body) => entry.body = std::mem::take(body).into(),
+            CacheMissBody::Disk(file, tmp) => {
+                file.flush().await.map_err(io_error)?;
+                if let (Some(tmp), Some(path)) = (tmp.take(), self.storage.path(&self.hash, "body"))
+                {
+                    tokio::fs::rename(&tmp, &path).await.map_err(io_error)?;
+                }
+                self.storage.write_entry(&self.hash, &entry).await?;
+            }
+        }
+        let size = entry.size + entry.meta.0.len() + entry.meta.1.len();
+        self.storage
+            .entries
+            .write()
+            .unwrap()
+            .insert(self.hash.clone(), entry);
+        Ok(MissFinishType::Created(size))
+    }
+}
+
+impl Drop for CacheMiss {
+    /// An unfinished response is never looked up
+    fn drop(&mut self) {
+        if let CacheMissBody::Disk(_, Some(tmp)) = &self.body {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pingora::cache::trace::Span;
+
+    use super::*;
+
+    fn config() -> GatewayCacheConfig {
+        GatewayCacheConfig {
+            storage: None,
+            default_ttl_seconds: None,
+            key_query: None,
+            key_host: None,
+            key_app_id: Some(true),
+            vary_headers: Some(vec!["X-Tenant".to_string()]),
+            stale_while_revalidate_seconds: Some(30),
+            stale_if_error_seconds: None,
+            collapse_misses: None,
+            max_file_size: None,
+        }
+    }
+
+    fn response(headers: &[(HeaderName, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(name, *value).unwrap();
+        }
+        resp
+    }
+
+    fn meta(cacheable: RespCacheable) -> Option<CacheMeta> {
+        match cacheable {
+            RespCacheable::Cacheable(meta) => Some(meta),
+            RespCacheable::Uncacheable(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let storage: &'static CacheStorage = Box::leak(Box::new(CacheStorage::memory(1 << 20)));
+        let cache = RouteCache::new(&config(), storage, false).unwrap();
+        let mut req = RequestHeader::build("GET", b"/items?page=2", None).unwrap();
+        req.insert_header(header::HOST, "api.example.com").unwrap();
+        let key = cache.key("items", &req);
+        assert_eq!(key.primary_key(), "/items?page=2");
+
+        // freshness of the upstream
+        let resp = response(&[(header::CACHE_CONTROL, "max-age=60")]);
+        let fresh = meta(cache.cacheable(&req, &resp, None)).unwrap();
+        assert!(fresh.is_fresh(SystemTime::now()));
+        assert_eq!(fresh.stale_while_revalidate_sec(), 30);
+        assert!(meta(cache.cacheable(&req, &response(&[]), None)).is_none());
+        let private = response(&[(header::CACHE_CONTROL, "private, max-age=60")]);
+        assert!(meta(cache.cacheable(&req, &private, None)).is_none());
+        let cookie = response(&[
+            (header::CACHE_CONTROL, "max-age=60"),
+            (header::SET_COOKIE, "session=1"),
+        ]);
+        assert!(meta(cache.cacheable(&req, &cookie, None)).is_none());
+
+        // responses to applications are only shared when public, unless the
+        // route keeps them apart
+        let shared = GatewayCacheConfig {
+            key_app_id: None,
+            ..config()
+        };
+        let public = response(&[(header::CACHE_CONTROL, "public, max-age=60")]);
+        let cache = RouteCache::new(&shared, storage, false).unwrap();
+        assert!(meta(cache.cacheable(&req, &resp, Some("app1"))).is_none());
+        assert!(meta(cache.cacheable(&req, &public, Some("app1"))).is_some());
+        let cache = RouteCache::new(&shared, storage, true).unwrap();
+        assert!(meta(cache.cacheable(&req, &resp, Some("app1"))).is_some());
+
+        // the default TTL only fills in for missing freshness
+        let cache = RouteCache::new(
+            &GatewayCacheConfig {
+                default_ttl_seconds: Some(60),
+                ..config()
+            },
+            storage,
+            false,
+        )
+        .unwrap();
+        assert!(meta(cache.cacheable(&req, &response(&[]), None)).is_some());
+        let no_store = response(&[(header::CACHE_CONTROL, "no-store")]);
+        assert!(meta(cache.cacheable(&req, &no_store, None)).is_none());
+        let expired = response(&[(header::EXPIRES, "Thu, 01 Jan 1970 00:00:00 GMT")]);
+        assert!(!meta(cache.cacheable(&req, &expired, None))
+            .unwrap()
+            .is_fresh(SystemTime::now()));
+
+        // variants by the Vary of the upstream, the route headers and app id
+        let varied =
+            meta(cache.cacheable(&req, &response(&[(header::VARY, "Accept-Language")]), None))
+                .unwrap();
+        let variance = |req: &RequestHeader, app_id| cache.variance(&varied, req, app_id);
+        let english = variance(&req, Some("app1"));
+        assert_eq!(english, variance(&req, Some("app1")));
+        assert_ne!(english, variance(&req, Some("app2")));
+        let mut other = req.clone();
+        other.insert_header(header::ACCEPT_LANGUAGE, "de").unwrap();
+        assert_ne!(english, variance(&other, Some("app1")));
+        let mut other = req.clone();
+        other.insert_header("X-Tenant", "t2").unwrap();
+        assert_ne!(english, variance(&other, Some("app1")));
+        let mut other = req.clone();
+        other
+            .insert_header(header::HOST, "www.example.com")
+            .unwrap();
+        assert_ne!(english, variance(&other, Some("app1")));
+
+        // stored, looked up and purged by prefix
+        let trace = Span::inactive().handle();
+        let mut miss = storage
+            .get_miss_handler(&key, &fresh, &trace)
+            .await
+            .unwrap();
+        miss.write_body(Bytes::from_static(b"[1, 2]"), true)
+            .await
+            .unwrap();
+        miss.finish().await.unwrap();
+        let (_, mut hit) = storage.lookup(&key, &trace).await.unwrap().unwrap();
+        assert_eq!(hit.read_body().await.unwrap().unwrap(), "[1, 2]");
+        assert_eq!(storage.purge_matching(Some("users"), |_| true), 0);
+        assert_eq!(
+            storage.purge_matching(Some("items"), |key| key.starts_with("/items")),
+            1
+        );
+        assert!(storage.lookup(&key, &trace).await.unwrap().is_none());
+    }
+}
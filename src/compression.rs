@@ -0,0 +1,329 @@
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::compression::{Algorithm, Encode},
+};
+
+use crate::config::GatewayCompressionConfig;
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/problem+json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml",
+];
+
+/// Compresses or decompresses a response body chunk by chunk
+pub type Encoder = Box<dyn Encode + Send + Sync>;
+
+/// The response compression of a route
+pub struct Compression {
+    /// In order of preference
+    algorithms: Vec<(Algorithm, u32)>,
+    min_size: usize,
+    content_types: Vec<String>,
+    decompress: bool,
+}
+
+fn algorithm(name: &str) -> anyhow::Result<Algorithm> {
+    match name {
+        "br" => Ok(Algorithm::Brotli),
+        "zstd" => Ok(Algorithm::Zstd),
+        "gzip" => Ok(Algorithm::Gzip),
+        other => anyhow::bail!("unknown compression algorithm: {}", other),
+    }
+}
+
+impl Compression {
+    pub fn new(config: &GatewayCompressionConfig) -> anyhow::Result<Self> {
+        let levels = config.levels.clone().unwrap_or_default();
+        for name in levels.keys() {
+            algorithm(name)?;
+        }
+        let names = config
+            .algorithms
+            .clone()
+            .unwrap_or_else(|| vec!["br".into(), "zstd".into(), "gzip".into()]);
+        let algorithms = names
+            .iter()
+            .map(|name| {
+                let (default, max) = match algorithm(name)? {
+                    Algorithm::Brotli => (4, 11),
+                    Algorithm::Zstd => (3, 22),
+                    _ => (6, 9),
+                };
+                let level = levels.get(name).copied().unwrap_or(default);
+                if !(1..=max).contains(&level) {
+                    anyhow::bail!("{} level {} is not in 1..={}", name, level, max);
+                }
+                Ok((algorithm(name)?, level))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            algorithms,
+            min_size: config.min_size.unwrap_or(DEFAULT_MIN_SIZE),
+            content_types: match &config.content_types {
+                Some(types) => types.iter().map(|t| t.to_ascii_lowercase()).collect(),
+                None => DEFAULT_CONTENT_TYPES
+                    .iter()
+                    .map(|t| t.to_string())
+                    .collect(),
+            },
+            decompress: config.decompress.unwrap_or(false),
+        })
+    }
+
+    fn compressible(&self, resp: &ResponseHeader) -> bool {
+        let small = resp
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|len| len < self.min_size);
+        let Some(content_type) = resp
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+        else {
+            return false;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        !small
+            && self
+                .content_types
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('*') {
+                    Some(prefix) => media_type.starts_with(prefix),
+                    None => media_type == *allowed,
+                })
+    }
+
+    /// The encoder of the response body, `None` when the response is sent
+    /// as it is. The response headers are changed to match the new body.
+    pub fn encoder(&self, req: &RequestHeader, resp: &mut ResponseHeader) -> Option<Encoder> {
+        if req.method == Method::HEAD
+            || resp.status.is_informational()
+            || matches!(
+                resp.status,
+                StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+            )
+        {
+            return None;
+        }
+        let accept_encoding = req
+            .headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok());
+        let content_encoding = resp
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| v != "identity");
+
+        let encoder = match content_encoding {
+            // already compressed, only undone for clients without support
+            Some(encoding) => {
+                if !self.decompress {
+                    return None;
+                }
+                add_vary(resp);
+                if accepts(accept_encoding, &encoding) {
+                    return None;
+                }
+                let encoder = algorithm(&encoding).ok()?.decompressor(true)?;
+                resp.remove_header(&header::CONTENT_ENCODING);
+                encoder
+            }
+            None => {
+                if !self.compressible(resp) {
+                    return None;
+                }
+                add_vary(resp);
+                // without the header any coding is acceptable, which old
+                // clients would not understand
+                accept_encoding?;
+                let (algorithm, level) = self
+                    .algorithms
+                    .iter()
+                    .find(|(algorithm, _)| accepts(accept_encoding, algorithm.as_str()))?;
+                let encoder = algorithm.compressor(*level)?;
+                resp.insert_header(header::CONTENT_ENCODING, algorithm.as_str())
+                    .ok()?;
+                encoder
+            }
+        };
+
+        // the length is unknown until the body is through the encoder
+        resp.remove_header(&header::CONTENT_LENGTH);
+        resp.remove_header(&header::ACCEPT_RANGES);
+        resp.insert_header(header::TRANSFER_ENCODING, "chunked")
+            .ok()?;
+        // the bytes differ from the ones of the upstream tag
+        if let Some(etag) = resp.headers.get(header::ETAG).cloned() {
+            match etag.as_bytes() {
+                [b'W', b'/', ..] => {}
+                [b'"', ..] => {
+                    let mut weak = b"W/".to_vec();
+                    weak.extend_from_slice(etag.as_bytes());
+                    resp.insert_header(header::ETAG, HeaderValue::from_bytes(&weak).ok()?)
+                        .ok()?;
+                }
+                _ => {
+                    resp.remove_header(&header::ETAG);
+                }
+            }
+        }
+        Some(encoder)
+    }
+}
+
+/// Whether the `Accept-Encoding` of a request allows `coding`, a missing
+/// header allows any
+fn accepts(accept_encoding: Option<&str>, coding: &str) -> bool {
+    let Some(accept_encoding) = accept_encoding else {
+        return true;
+    };
+    let mut any = None;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            any = Some(q > 0.0);
+        }
+    }
+    any.unwrap_or(false)
+}
+
+fn add_vary(resp: &mut ResponseHeader) {
+    let varies = resp
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        let _ = resp.append_header(header::VARY, "Accept-Encoding");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn response(content_type: &str, len: usize) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::CONTENT_TYPE, content_type)
+            .unwrap();
+        resp.insert_header(header::CONTENT_LENGTH, len.to_string())
+            .unwrap();
+        resp.insert_header(header::ETAG, "\"v1\"").unwrap();
+        resp
+    }
+
+    fn request(accept_encoding: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(accept_encoding) = accept_encoding {
+            req.insert_header(header::ACCEPT_ENCODING, accept_encoding)
+                .unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_compression() {
+        assert!(accepts(Some("gzip, br;q=0.5"), "br"));
+        assert!(!accepts(Some("gzip, br;q=0"), "br"));
+        assert!(accepts(Some("*;q=0.1"), "zstd"));
+        assert!(!accepts(Some("*, zstd;q=0"), "zstd"));
+        assert!(!accepts(Some("gzip"), "br"));
+
+        let compression = Compression::new(&GatewayCompressionConfig {
+            algorithms: Some(vec!["zstd".into(), "gzip".into()]),
+            levels: Some(HashMap::from([("gzip".into(), 9)])),
+            min_size: None,
+            content_types: None,
+            decompress: Some(true),
+        })
+        .unwrap();
+
+        // the route preference decides, not the order of the client
+        let mut resp = response("application/json; charset=utf-8", 4096);
+        let mut encoder = compression
+            .encoder(&request(Some("gzip, deflate, zstd")), &mut resp)
+            .unwrap();
+        assert_eq!(resp.headers[header::CONTENT_ENCODING], "zstd");
+        assert_eq!(resp.headers[header::ETAG], "W/\"v1\"");
+        assert_eq!(resp.headers[header::VARY], "Accept-Encoding");
+        assert!(resp.headers.get(header::CONTENT_LENGTH).is_none());
+        assert!(!encoder.encode(&[b'a'; 4096], true).unwrap().is_empty());
+
+        let mut resp = response("application/json", 4096);
+        assert!(compression
+            .encoder(&request(Some("br")), &mut resp)
+            .is_none());
+        assert_eq!(resp.headers[header::VARY], "Accept-Encoding");
+        let mut resp = response("text/html", 100);
+        assert!(compression
+            .encoder(&request(Some("gzip")), &mut resp)
+            .is_none());
+        let mut resp = response("image/png", 4096);
+        assert!(compression
+            .encoder(&request(Some("gzip")), &mut resp)
+            .is_none());
+        let mut resp = response("text/html", 4096);
+        assert!(compression.encoder(&request(None), &mut resp).is_none());
+
+        // compressed by the upstream
+        let mut resp = response("text/html", 4096);
+        resp.insert_header(header::CONTENT_ENCODING, "br").unwrap();
+        assert!(compression
+            .encoder(&request(Some("gzip, br")), &mut resp)
+            .is_none());
+        assert!(compression
+            .encoder(&request(Some("zstd")), &mut resp)
+            .is_some());
+        assert!(resp.headers.get(header::CONTENT_ENCODING).is_none());
+
+        let invalid = |algorithms: Vec<String>, levels: HashMap<String, u32>| {
+            Compression::new(&GatewayCompressionConfig {
+                algorithms: Some(algorithms),
+                levels: Some(levels),
+                min_size: None,
+                content_types: None,
+                decompress: None,
+            })
+            .is_err()
+        };
+        assert!(invalid(vec!["deflate".into()], HashMap::new()));
+        assert!(invalid(
+            vec!["gzip".into()],
+            HashMap::from([("gzip".into(), 10)])
+        ));
+        assert!(invalid(
+            vec!["gzip".into()],
+            HashMap::from([("lz4".into(), 1)])
+        ));
+    }
+}
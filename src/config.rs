@@ -1,19 +1,159 @@
-use std::{fs::File, io::BufReader};
+use std::{collections::HashMap, fs::File, io::BufReader};
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GatewayConfig {
+    pub listeners: Option<Vec<GatewayListenerConfig>>,
+    /// Shared state of the distributed application rate limits
+    pub redis: Option<GatewayRedisConfig>,
+    /// Limits callers without a known application id, keyed by client ip
+    /// unless `key` is set
+    pub default_rate_limit: Option<GatewayRateLimitConfig>,
+    /// Headers reporting the limit, "ietf" (default), "x" or "none"
+    pub rate_limit_headers: Option<String>,
+    /// Where quota usage is persisted, defaults to "gateway_quota.json"
+    pub quota_file: Option<String>,
+    /// Where API keys issued by the admin api are persisted, defaults to
+    /// "gateway_api_keys.json"
+    pub api_key_file: Option<String>,
+    /// Client addresses every route is restricted to
+    pub ip_access: Option<GatewayIpAccessConfig>,
+    /// Client addresses the "/admin" route is restricted to
+    pub admin_ip_access: Option<GatewayIpAccessConfig>,
+    /// Where the responses of routes with a `cache` are kept
+    pub cache: Option<GatewayCacheStorageConfig>,
     pub backgrounds: Option<Vec<String>>,
     pub applications: Option<Vec<GatewayApplicationConfig>>,
     pub load_balancers: Option<Vec<GatewayLoadBalancerConfig>>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayListenerConfig {
+    /// "host:port" or "unix:/path/to.sock"
+    pub address: String,
+    pub proxy_protocol: Option<GatewayProxyProtocolConfig>,
+    /// Serve TLS on a tcp listener, a unix listener or one with
+    /// `proxy_protocol` is rejected at startup
+    pub tls: Option<GatewayTlsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayTlsConfig {
+    /// PEM certificate chain and private key of the listener
+    pub cert: String,
+    pub key: String,
+    /// PEM bundle of the CAs client certificates are verified against
+    pub client_ca: Option<String>,
+    /// "none" (default), "request" or "require" a client certificate
+    pub client_auth: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayProxyProtocolConfig {
+    /// Only connections from these sources may send a PROXY protocol header
+    pub trusted_cidrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayIpAccessConfig {
+    /// Only clients in these CIDR ranges are served when set, e.g.,
+    /// ["10.0.0.0/8", "2001:db8::/32"]
+    pub allow: Option<Vec<String>>,
+    /// Clients never served, even within `allow`
+    pub deny: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GatewayCacheStorageConfig {
+    /// Bytes of the in-memory cache, 64 MiB by default
+    pub memory_max_size: Option<usize>,
+    /// A cache on disk, kept across restarts
+    pub disk: Option<GatewayDiskCacheConfig>,
+    /// How long the requests collapsed into a miss wait for it, 10 by default
+    pub lock_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GatewayDiskCacheConfig {
+    /// The directory of the cached responses, created when missing
+    pub path: String,
+    /// Bytes of the cache, 1 GiB by default
+    pub max_size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GatewayRedisConfig {
+    /// e.g., "redis://127.0.0.1:6379"
+    pub url: String,
+    /// Prefix of the rate limit keys, defaults to "gateway:rate_limit:"
+    pub key_prefix: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GatewayApplicationConfig {
     pub app_id: String,
     pub limit_interval_seconds: u64,
     pub limit: u32,
+    /// "fixed_window" (default) or "token_bucket", a token bucket refills
+    /// `limit` tokens every `limit_interval_seconds`
+    pub algorithm: Option<String>,
+    /// The token bucket capacity, defaults to `limit`
+    pub burst: Option<u32>,
+    /// Share the limit across gateway instances through `redis`
+    pub distributed: Option<bool>,
+    pub quota: Option<GatewayQuotaConfig>,
+    /// The most requests of the application in flight at once
+    pub max_concurrent: Option<u32>,
+    /// How long a request over `max_concurrent` waits for a slot before it
+    /// is rejected, rejected at once by default
+    pub max_concurrent_queue_ms: Option<u64>,
+    /// What callers must present to act as the application
+    pub credentials: Option<GatewayCredentialsConfig>,
+    /// The routes the application may call, every route when unset
+    pub routes: Option<Vec<GatewayRouteAccessConfig>>,
+    pub ip_access: Option<GatewayIpAccessConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayRouteAccessConfig {
+    /// A route name or a glob, e.g., "orders-*"
+    pub route: String,
+    /// The allowed methods, every method when unset
+    pub methods: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayCredentialsConfig {
+    /// SHA-256 hex digests of the accepted `X-GATEWAY-APIKEY` values,
+    /// several keys may be active while they are rotated
+    pub api_keys: Option<Vec<String>>,
+    /// Require requests signed with HMAC-SHA256 under this secret
+    pub hmac_secret: Option<String>,
+    /// How far the timestamp of a signed request may be off, 300 by default
+    pub hmac_max_skew_seconds: Option<u64>,
+    /// Require a client certificate, which also identifies the application
+    /// without an `X-GATEWAY-APPID` header
+    pub client_cert: Option<GatewayClientCertConfig>,
+}
+
+/// The client certificates of an application, every given field must match
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayClientCertConfig {
+    /// Subject attributes the certificate must have, e.g., "CN=partner,O=Acme"
+    pub subject: Option<String>,
+    /// A DNS, email, URI or IP subject alternative name of the certificate
+    pub san: Option<String>,
+    /// The SHA-256 fingerprint in hex, colons are ignored
+    pub fingerprint: Option<String>,
+}
+
+/// A long-period request quota, e.g., 1M requests per month
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayQuotaConfig {
+    pub limit: u64,
+    /// "day" or "month", calendar periods in UTC
+    pub period: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -21,13 +161,224 @@ pub struct GatewayLoadBalancerConfig {
     pub name: String,
     pub match_rule: GatewayLoadBalancerMatchRuleConfig,
     pub rewrite: Option<GatewayRewriteConfig>,
+    /// "static" or "docker", not needed by `redirect` and `respond` routes
+    #[serde(default)]
     pub service_discovery: String,
     pub upstream: Option<Vec<String>>,
+    /// The route protocol, "http" (default), "grpc" or "tcp"
+    pub protocol: Option<String>,
+    /// The address a "tcp" route listens on, e.g., "0.0.0.0:5432"
+    pub listen: Option<String>,
+    /// Accept the PROXY protocol on the listener of a "tcp" route
+    pub proxy_protocol: Option<GatewayProxyProtocolConfig>,
+    /// Send a PROXY protocol header to the upstreams, "v1" or "v2"
+    pub upstream_proxy_protocol: Option<String>,
+    pub streaming: Option<GatewayStreamingConfig>,
+    pub grpc: Option<GatewayGrpcConfig>,
+    /// Limits all requests to the route, whichever client sends them
+    pub rate_limit: Option<GatewayRateLimitConfig>,
+    /// Require a valid bearer JWT on every request to the route
+    pub jwt: Option<GatewayJwtConfig>,
+    /// Only serve requests of known applications
+    pub require_application: Option<bool>,
+    /// Ask an auth service whether to serve each request
+    pub forward_auth: Option<GatewayForwardAuthConfig>,
+    pub ip_access: Option<GatewayIpAccessConfig>,
+    /// Changes to the request sent to the upstream
+    pub request_headers: Option<GatewayHeadersConfig>,
+    /// Changes to the response sent to the client
+    pub response_headers: Option<GatewayHeadersConfig>,
+    /// Answer with a redirect instead of proxying
+    pub redirect: Option<GatewayRedirectConfig>,
+    /// Answer with a fixed response instead of proxying
+    pub respond: Option<GatewayRespondConfig>,
+    /// Serve the files of a directory instead of proxying
+    pub static_files: Option<GatewayStaticFilesConfig>,
+    /// Compress the upstream responses for clients that accept it
+    pub compression: Option<GatewayCompressionConfig>,
+    /// Answer CORS preflights and add the CORS headers to the responses
+    pub cors: Option<GatewayCorsConfig>,
+    /// Cache the GET and HEAD responses of the upstream
+    pub cache: Option<GatewayCacheConfig>,
+}
+
+/// Responses are fresh for as long as their Cache-Control or Expires say,
+/// the options only fill in what the upstream leaves out
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayCacheConfig {
+    /// "memory" (default) or "disk", the latter needs the global `cache.disk`
+    pub storage: Option<String>,
+    /// How long responses without Cache-Control or Expires are fresh, they
+    /// are not cached by default
+    pub default_ttl_seconds: Option<u32>,
+    /// Keep responses to different query strings apart, on by default
+    pub key_query: Option<bool>,
+    /// Keep responses to different Host headers apart, on by default
+    pub key_host: Option<bool>,
+    /// Keep the responses of each application apart, the default on routes
+    /// with `require_application`. Otherwise responses to an application are
+    /// only cached when `public` or `s-maxage`, like Authorization requests.
+    pub key_app_id: Option<bool>,
+    /// Request headers the responses vary by besides the Vary of the upstream
+    pub vary_headers: Option<Vec<String>>,
+    /// Serve stale responses while they are fetched again in the background
+    pub stale_while_revalidate_seconds: Option<u32>,
+    /// Serve stale responses when the upstream fails
+    pub stale_if_error_seconds: Option<u32>,
+    /// Send only the first of concurrent misses upstream, the others wait
+    /// for its response, on by default and needed by stale-while-revalidate
+    pub collapse_misses: Option<bool>,
+    /// Larger responses are not cached
+    pub max_file_size: Option<usize>,
+}
+
+/// The CORS headers of the upstream are replaced by the ones of the policy
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayCorsConfig {
+    /// Exact origins, e.g., "https://app.example.com", or "*" for any
+    pub allow_origins: Option<Vec<String>>,
+    /// Regexes matched against the whole origin, e.g.,
+    /// "https://.*\\.example\\.com"
+    pub allow_origin_regex: Option<Vec<String>>,
+    /// GET, HEAD and POST by default
+    pub allow_methods: Option<Vec<String>>,
+    /// The request headers a preflight asks for are allowed by default
+    pub allow_headers: Option<Vec<String>>,
+    /// Allow cookies and authorization headers, the origin is then always
+    /// sent back instead of "*"
+    pub allow_credentials: Option<bool>,
+    /// How long browsers may cache a preflight answer, in seconds
+    pub max_age: Option<u64>,
+    /// Response headers scripts may read besides the simple ones
+    pub expose_headers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayCompressionConfig {
+    /// "br", "zstd" and "gzip" in order of preference, all three by default
+    pub algorithms: Option<Vec<String>>,
+    /// Levels by algorithm, by default br 4, zstd 3 and gzip 6
+    pub levels: Option<HashMap<String, u32>>,
+    /// Smaller responses are sent as they are, 1024 by default
+    pub min_size: Option<usize>,
+    /// Compressed media types, "text/*" matches all text types. By default
+    /// text, JSON, JavaScript, XML and SVG.
+    pub content_types: Option<Vec<String>>,
+    /// Decompress gzip and br responses of the upstream for clients that
+    /// do not accept them
+    pub decompress: Option<bool>,
+}
+
+/// Files are looked up by the request path after the `rewrite` regex, e.g.,
+/// "^/app" replaced with "" serves "/app/main.js" from "<root>/main.js"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayStaticFilesConfig {
+    pub root: String,
+    /// The files served for a directory, ["index.html"] by default
+    pub index: Option<Vec<String>>,
+    /// Serve the root index for missing paths without a file extension,
+    /// the client side routes of a single page application
+    pub spa_fallback: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayRedirectConfig {
+    /// 301, 302 (default), 303, 307 or 308
+    pub status: Option<u16>,
+    /// The Location, a template like the header values, e.g.,
+    /// "https://new.example.com/$1" with the groups of the `rewrite` regex
+    pub target: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayRespondConfig {
+    /// 200 by default
+    pub status: Option<u16>,
+    pub headers: Option<HashMap<String, String>>,
+    /// The inline body, or the `file` it is read from once
+    pub body: Option<String>,
+    pub file: Option<String>,
+}
+
+/// Header values may use `$client_ip`, `$app_id`, `$route`, `$upstream` and
+/// the groups of the `rewrite` regex, e.g., `$1` or `${version}`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayHeadersConfig {
+    /// Headers replaced with the value
+    pub set: Option<HashMap<String, String>>,
+    /// Headers added next to any existing value
+    pub add: Option<HashMap<String, String>>,
+    /// Headers removed before any is set or added
+    pub remove: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayForwardAuthConfig {
+    pub url: String,
+    /// Request headers sent to the auth service, e.g., ["Authorization", "Cookie"]
+    pub request_headers: Option<Vec<String>>,
+    /// Headers of an allowing answer copied to the upstream request
+    pub response_headers: Option<Vec<String>>,
+    /// How long answers are reused for the same request, not cached by default
+    pub cache_ttl_seconds: Option<u64>,
+    /// 5000 by default
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayJwtConfig {
+    pub issuers: Option<Vec<String>>,
+    pub audiences: Option<Vec<String>>,
+    /// e.g., "RS256", "ES256" or "HS256", RS256 by default
+    pub algorithms: Option<Vec<String>>,
+    /// Where the keys are fetched from, either this or `key_file`
+    pub jwks_url: Option<String>,
+    /// A local JWKS, PEM public key or HMAC secret
+    pub key_file: Option<String>,
+    /// How often the JWKS is fetched again, 300 by default
+    pub jwks_refresh_seconds: Option<u64>,
+    /// Claims every token must carry
+    pub required_claims: Option<Vec<String>>,
+    /// Claims forwarded to the upstream, claim name to header name
+    pub forward_claims: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayRateLimitConfig {
+    pub limit_interval_seconds: u64,
+    pub limit: u32,
+    /// "fixed_window" (default) or "token_bucket"
+    pub algorithm: Option<String>,
+    pub burst: Option<u32>,
+    /// Count the limit per client, a composite of "ip", "ip/24",
+    /// "header:<name>", "claim:<name>" or "app_id". Claims are taken from
+    /// the JWT verified by the route, so they need its `jwt` policy
+    pub key: Option<Vec<String>>,
+}
+
+/// Enables websocket and server-sent events handling on a route
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayStreamingConfig {
+    /// Idle timeout of ordinary requests
+    pub idle_timeout_seconds: Option<u64>,
+    /// Idle timeout of upgraded websocket and event-stream connections
+    pub stream_idle_timeout_seconds: Option<u64>,
+}
+
+/// Options of a "grpc" route
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayGrpcConfig {
+    /// Check backends with grpc.health.v1, defaults to on for docker
+    /// discovery and off for static upstreams
+    pub health_check: Option<bool>,
+    /// The service name to check, the whole server when unset
+    pub health_service: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GatewayLoadBalancerMatchRuleConfig {
-    /// The type of match rule, e.g., "path_start_with" or "path_regex"
+    /// The type of match rule, e.g., "path_start_with" or "path_regex",
+    /// "grpc_service" for grpc routes, "sni" or "any" for tcp routes
     #[serde(rename = "type")]
     pub typ: String,
     pub value: String,
@@ -35,8 +386,29 @@ pub struct GatewayLoadBalancerMatchRuleConfig {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GatewayRewriteConfig {
-    pub regex: String,
-    pub replacement: String,
+    /// The path regex and its replacement, e.g., "^/api/(.*)" and "/$1"
+    pub regex: Option<String>,
+    pub replacement: Option<String>,
+    /// The Host header sent to the upstream, also the TLS server name
+    pub host: Option<String>,
+    /// Keep the Host of the client (default) rather than send the upstream
+    /// address, unless `host` is set
+    pub preserve_host: Option<bool>,
+    /// "http" (default) or "https" to the upstream
+    pub scheme: Option<String>,
+    /// Prepended to the upstream path, after the regex replacement
+    pub path_prefix: Option<String>,
+    pub query: Option<GatewayQueryRewriteConfig>,
+}
+
+/// Query parameters removed, renamed and added, in that order
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayQueryRewriteConfig {
+    /// Appended in name order
+    pub add: Option<HashMap<String, String>>,
+    pub remove: Option<Vec<String>>,
+    /// Old names to new names
+    pub rename: Option<HashMap<String, String>>,
 }
 
 impl GatewayConfig {
@@ -45,4 +417,14 @@ impl GatewayConfig {
         let config: GatewayConfig = serde_yaml::from_reader(BufReader::new(file))?;
         Ok(config)
     }
+
+    pub fn listeners(&self) -> Vec<GatewayListenerConfig> {
+        self.listeners.clone().unwrap_or_else(|| {
+            vec![GatewayListenerConfig {
+                address: "0.0.0.0:6188".to_string(),
+                proxy_protocol: None,
+                tls: None,
+            }]
+        })
+    }
 }
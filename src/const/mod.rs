@@ -2,9 +2,23 @@ pub const DOCKER_LABEL_DOCKER_COMPOSE_SERVICE: &str = "com.docker.compose.servic
 pub const DOCKER_LABEL_GATEWAY_HOST_IP: &str = "com.gateway.host.ip";
 pub const DOCKER_LABEL_GATEWAY_MODE: &str = "com.gateway.mode";
 pub const DOCKER_LABEL_GATEWAY_CONNECT_NETWORK: &str = "com.gateway.connect.network";
+pub const DOCKER_LABEL_GATEWAY_UNIX_SOCKET: &str = "com.gateway.unix.socket";
+
+/// Prefix of unix domain socket addresses, e.g., "unix:/run/app.sock"
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
 
 pub const GATEWAY_HEADER_EXT: &str = "X-GATEWAY-EXT";
 pub const GATEWAY_QUERY_EXT: &str = "x-gateway-ext";
 pub const GATEWAY_APPID: &str = "X-GATEWAY-APPID";
+pub const GATEWAY_APIKEY: &str = "X-GATEWAY-APIKEY";
+pub const GATEWAY_TIMESTAMP: &str = "X-GATEWAY-TIMESTAMP";
+pub const GATEWAY_NONCE: &str = "X-GATEWAY-NONCE";
+pub const GATEWAY_SIGNATURE: &str = "X-GATEWAY-SIGNATURE";
+/// How the cache served a response, e.g., "hit", "miss" or "stale"
+pub const GATEWAY_CACHE_STATUS: &str = "X-Cache-Status";
 
 pub const DOCKER_BACKGROUND_SERVICE_NAME: &str = "docker_background_service";
+pub const QUOTA_STORE_SERVICE_NAME: &str = "quota_store_service";
+
+/// The http proxy listens here for connections forwarded by the proxy protocol listeners
+pub const GATEWAY_INTERNAL_LISTEN: &str = "127.0.0.1:16188";
@@ -0,0 +1,318 @@
+use axum::http::{header, HeaderName, Method};
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::Regex;
+
+use crate::config::GatewayCorsConfig;
+
+/// The CORS policy of a route
+pub struct CorsPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    origin_regex: Vec<Regex>,
+    methods: Vec<Method>,
+    /// `None` allows the headers a preflight asks for
+    headers: Option<Vec<HeaderName>>,
+    credentials: bool,
+    max_age: Option<u64>,
+    expose_headers: Vec<HeaderName>,
+}
+
+impl CorsPolicy {
+    pub fn new(config: &GatewayCorsConfig) -> anyhow::Result<Self> {
+        let origins = config.allow_origins.clone().unwrap_or_default();
+        let origin_regex = config
+            .allow_origin_regex
+            .iter()
+            .flatten()
+            .map(|regex| Regex::new(&format!("^(?:{})$", regex)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if origins.is_empty() && origin_regex.is_empty() {
+            anyhow::bail!("one of allow_origins and allow_origin_regex is required");
+        }
+        let names = |names: &Option<Vec<String>>| {
+            names
+                .iter()
+                .flatten()
+                .map(|name| HeaderName::try_from(name.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let methods = match &config.allow_methods {
+            Some(methods) => methods
+                .iter()
+                .map(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()))
+                .collect::<Result<_, _>>()?,
+            None => vec![Method::GET, Method::HEAD, Method::POST],
+        };
+        Ok(Self {
+            any_origin: origins.iter().any(|origin| origin == "*"),
+            origins: origins.into_iter().filter(|origin| origin != "*").collect(),
+            origin_regex,
+            methods,
+            headers: match &config.allow_headers {
+                Some(_) => Some(names(&config.allow_headers)?),
+                None => None,
+            },
+            credentials: config.allow_credentials.unwrap_or(false),
+            max_age: config.max_age,
+            expose_headers: names(&config.expose_headers)?,
+        })
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.origins.iter().any(|allowed| allowed == origin)
+            || self.origin_regex.iter().any(|regex| regex.is_match(origin))
+    }
+
+    /// The Access-Control-Allow-Origin of an allowed origin. Credentials
+    /// are never allowed for "*", so the origin itself is sent back then.
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if !self.allows_origin(origin) {
+            return None;
+        }
+        match self.any_origin && !self.credentials {
+            true => Some("*"),
+            false => Some(origin),
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed.as_str() == method)
+    }
+
+    /// The answer to a preflight, `Err` with the reason when the origin,
+    /// method or one of the headers is not allowed
+    pub fn preflight(&self, req: &RequestHeader) -> Result<ResponseHeader, &'static str> {
+        let request_header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        let allow_origin = request_header(header::ORIGIN)
+            .and_then(|origin| self.allow_origin(origin))
+            .ok_or("origin not allowed")?;
+        let method = request_header(header::ACCESS_CONTROL_REQUEST_METHOD).unwrap_or_default();
+        if !self.allows_method(method) {
+            return Err("method not allowed");
+        }
+        let requested = request_header(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty());
+        let allow_headers = match &self.headers {
+            Some(allowed) => {
+                let any = allowed.iter().any(|name| name == "*") && !self.credentials;
+                for name in requested {
+                    if !any
+                        && !allowed
+                            .iter()
+                            .any(|a| a.as_str().eq_ignore_ascii_case(name))
+                    {
+                        return Err("header not allowed");
+                    }
+                }
+                join(allowed)
+            }
+            None => requested.collect::<Vec<_>>().join(", "),
+        };
+
+        let build = || -> pingora::Result<ResponseHeader> {
+            let mut header = ResponseHeader::build(204, Some(7))?;
+            header.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)?;
+            let methods = self.methods.iter().map(Method::as_str);
+            header.insert_header(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                methods.collect::<Vec<_>>().join(", "),
+            )?;
+            if !allow_headers.is_empty() {
+                header.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, &allow_headers)?;
+            }
+            if self.credentials {
+                header.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+            }
+            if let Some(max_age) = self.max_age {
+                header.insert_header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string())?;
+            }
+            header.insert_header(header::VARY, "Origin")?;
+            header.insert_header(header::CONTENT_LENGTH, "0")?;
+            Ok(header)
+        };
+        build().map_err(|_| "invalid preflight request")
+    }
+
+    /// Replace the CORS headers of the upstream with the ones of the policy
+    pub fn apply(&self, req: &RequestHeader, resp: &mut ResponseHeader) -> pingora::Result<()> {
+        for name in [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::ACCESS_CONTROL_MAX_AGE,
+        ] {
+            resp.remove_header(&name);
+        }
+        // caches must not hand the answer for one origin to another
+        if !self.any_origin || self.credentials {
+            resp.append_header(header::VARY, "Origin")?;
+        }
+        let Some(allow_origin) = req
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|origin| self.allow_origin(origin))
+        else {
+            return Ok(());
+        };
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)?;
+        if self.credentials {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        }
+        if !self.expose_headers.is_empty() {
+            resp.insert_header(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                join(&self.expose_headers),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the request is a CORS preflight rather than a plain OPTIONS
+pub fn is_preflight(req: &RequestHeader) -> bool {
+    req.method == Method::OPTIONS
+        && req.headers.contains_key(header::ORIGIN)
+        && req
+            .headers
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn join(names: &[HeaderName]) -> String {
+    names
+        .iter()
+        .map(HeaderName::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn preflight(origin: &str, method: &str, headers: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("OPTIONS", b"/orders", None).unwrap();
+        req.insert_header(header::ORIGIN, origin).unwrap();
+        req.insert_header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+            .unwrap();
+        if let Some(headers) = headers {
+            req.insert_header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+                .unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_cors_policy() {
+        let policy = CorsPolicy::new(&GatewayCorsConfig {
+            allow_origins: Some(vec!["https://app.example.com".to_string()]),
+            allow_origin_regex: Some(vec![r"https://[a-z0-9]+\.preview\.example\.com".to_string()]),
+            allow_methods: Some(vec!["get".to_string(), "PUT".to_string()]),
+            allow_headers: Some(vec!["Content-Type".to_string(), "X-Request-Id".to_string()]),
+            allow_credentials: Some(true),
+            max_age: Some(600),
+            expose_headers: Some(vec!["X-RateLimit-Remaining".to_string()]),
+        })
+        .unwrap();
+
+        let req = preflight(
+            "https://pr1.preview.example.com",
+            "PUT",
+            Some("content-type"),
+        );
+        assert!(is_preflight(&req));
+        let answer = policy.preflight(&req).unwrap();
+        assert_eq!(answer.status, 204);
+        assert_eq!(
+            answer.headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://pr1.preview.example.com"
+        );
+        assert_eq!(
+            answer.headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, PUT"
+        );
+        assert_eq!(answer.headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(
+            policy
+                .preflight(&preflight(
+                    "https://evil.example.com.attacker.net",
+                    "PUT",
+                    None
+                ))
+                .err(),
+            Some("origin not allowed")
+        );
+        assert_eq!(
+            policy
+                .preflight(&preflight("https://app.example.com", "DELETE", None))
+                .err(),
+            Some("method not allowed")
+        );
+        assert_eq!(
+            policy
+                .preflight(&preflight(
+                    "https://app.example.com",
+                    "GET",
+                    Some("X-Debug")
+                ))
+                .err(),
+            Some("header not allowed")
+        );
+
+        let mut req = RequestHeader::build("GET", b"/orders", None).unwrap();
+        req.insert_header(header::ORIGIN, "https://app.example.com")
+            .unwrap();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .unwrap();
+        policy.apply(&req, &mut resp).unwrap();
+        assert_eq!(
+            resp.headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            resp.headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS],
+            "true"
+        );
+        assert_eq!(resp.headers[header::VARY], "Origin");
+
+        // an upstream allowing any origin does not widen the policy
+        req.insert_header(header::ORIGIN, "https://other.example.com")
+            .unwrap();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .unwrap();
+        policy.apply(&req, &mut resp).unwrap();
+        assert!(resp
+            .headers
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let any = CorsPolicy::new(&GatewayCorsConfig {
+            allow_origins: Some(vec!["*".to_string()]),
+            allow_origin_regex: None,
+            allow_methods: None,
+            allow_headers: None,
+            allow_credentials: None,
+            max_age: None,
+            expose_headers: None,
+        })
+        .unwrap();
+        let answer = any
+            .preflight(&preflight("https://x.test", "POST", Some("X-A, X-B")))
+            .unwrap();
+        assert_eq!(answer.headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            answer.headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "X-A, X-B"
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use pingora::proxy::Session;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::{
+    mtls::{client_identity, ClientCertBinding, ClientIdentity},
+    r#const::{GATEWAY_APIKEY, GATEWAY_NONCE, GATEWAY_SIGNATURE, GATEWAY_TIMESTAMP},
+};
+
+/// The largest body of a signed request, it is buffered to be hashed
+const SIGNED_BODY_LIMIT: usize = 64 * 1024;
+/// Forget the nonces of expired timestamps once this many nonces are tracked
+const NONCE_PRUNE_SIZE: usize = 10_000;
+/// The length of the hash prefix identifying an API key
+const KEY_ID_LEN: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum CredentialError {
+    MissingApiKey,
+    InvalidApiKey,
+    MissingSignature,
+    InvalidTimestamp,
+    ReplayedNonce,
+    InvalidSignature,
+    BodyTooLarge,
+    InvalidClientCertificate,
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CredentialError::MissingApiKey => "missing api key",
+            CredentialError::InvalidApiKey => "invalid api key",
+            CredentialError::MissingSignature => "missing request signature",
+            CredentialError::InvalidTimestamp => "request timestamp out of range",
+            CredentialError::ReplayedNonce => "request nonce already used",
+            CredentialError::InvalidSignature => "invalid request signature",
+            CredentialError::BodyTooLarge => "signed request body too large",
+            CredentialError::InvalidClientCertificate => {
+                "client certificate does not match the application"
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+/// The hex SHA-256 digest an API key is stored as
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+struct ApiKey {
+    /// A prefix of the hash, enough to name the key when it is revoked
+    id: String,
+    hash: String,
+}
+
+impl ApiKey {
+    fn from_hash(hash: &str) -> Self {
+        let hash = hash.to_ascii_lowercase();
+        Self {
+            id: hash.chars().take(KEY_ID_LEN).collect(),
+            hash,
+        }
+    }
+}
+
+/// A newly issued API key, the key itself is only known to the caller
+pub struct IssuedApiKey {
+    pub id: String,
+    pub key: String,
+    pub hash: String,
+}
+
+/// Nonces seen with the timestamps they were signed at, kept outside the
+/// verifier so a rebuilt application still rejects replayed requests
+pub type Nonces = Arc<Mutex<HashMap<String, u64>>>;
+
+/// Requests signed with HMAC-SHA256 over method, path, timestamp, nonce and
+/// the body hash, each nonce is accepted once within the allowed skew
+pub struct HmacVerifier {
+    secret: Vec<u8>,
+    max_skew: Duration,
+    nonces: Nonces,
+}
+
+impl HmacVerifier {
+    pub fn new(secret: &str, max_skew: Duration, nonces: Nonces) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+            max_skew,
+            nonces,
+        }
+    }
+
+    fn string_to_sign(
+        method: &str,
+        path: &str,
+        timestamp: &str,
+        nonce: &str,
+        body_hash: &str,
+    ) -> String {
+        format!("{method}\n{path}\n{timestamp}\n{nonce}\n{body_hash}")
+    }
+
+    /// The hex signature of a request, as the client computes it
+    #[cfg(test)]
+    pub fn sign(
+        &self,
+        method: &str,
+        path: &str,
+        timestamp: &str,
+        nonce: &str,
+        body: &[u8],
+    ) -> String {
+        let body_hash = hex::encode(Sha256::digest(body));
+        let mut mac = self.mac();
+        mac.update(Self::string_to_sign(method, path, timestamp, nonce, &body_hash).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts keys of any size")
+    }
+
+    pub fn verify(
+        &self,
+        method: &str,
+        path: &str,
+        timestamp: &str,
+        nonce: &str,
+        body_hash: &str,
+        signature: &str,
+    ) -> Result<(), CredentialError> {
+        let signed_at = timestamp
+            .parse::<u64>()
+            .map_err(|_| CredentialError::InvalidTimestamp)?;
+        let now = unix_now();
+        if now.abs_diff(signed_at) > self.max_skew.as_secs() {
+            return Err(CredentialError::InvalidTimestamp);
+        }
+        let signature = hex::decode(signature).map_err(|_| CredentialError::InvalidSignature)?;
+        let mut mac = self.mac();
+        mac.update(Self::string_to_sign(method, path, timestamp, nonce, body_hash).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| CredentialError::InvalidSignature)?;
+
+        // only remember nonces of genuine requests
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.len() >= NONCE_PRUNE_SIZE {
+            let max_skew = self.max_skew.as_secs();
+            nonces.retain(|_, signed_at| now.abs_diff(*signed_at) <= max_skew);
+        }
+        if nonces.insert(nonce.to_string(), signed_at).is_some() {
+            return Err(CredentialError::ReplayedNonce);
+        }
+        Ok(())
+    }
+}
+
+/// The credentials a caller needs to act as an application
+#[derive(Default)]
+pub struct Credentials {
+    /// Accepted keys, `None` when the application takes no API key
+    api_keys: RwLock<Option<Vec<ApiKey>>>,
+    hmac: Option<HmacVerifier>,
+    client_cert: Option<ClientCertBinding>,
+}
+
+impl Credentials {
+    pub fn new(api_key_hashes: Option<&[String]>, hmac: Option<HmacVerifier>) -> Self {
+        let api_keys =
+            api_key_hashes.map(|hashes| hashes.iter().map(|h| ApiKey::from_hash(h)).collect());
+        Self {
+            api_keys: RwLock::new(api_keys),
+            hmac,
+            client_cert: None,
+        }
+    }
+
+    pub fn with_client_cert(mut self, client_cert: ClientCertBinding) -> Self {
+        self.client_cert = Some(client_cert);
+        self
+    }
+
+    /// Whether requests of the application have to prove their identity
+    pub fn required(&self) -> bool {
+        self.hmac.is_some() || self.client_cert.is_some() || self.api_keys.read().unwrap().is_some()
+    }
+
+    /// Whether the client certificate identifies the application
+    pub fn matches_client_cert(&self, identity: &ClientIdentity) -> bool {
+        self.client_cert
+            .as_ref()
+            .is_some_and(|binding| binding.matches(identity))
+    }
+
+    /// Accept the keys of the hashes as well, which turns on API key
+    /// authentication
+    pub fn with_api_keys(self, hashes: &[String]) -> Self {
+        self.api_keys
+            .write()
+            .unwrap()
+            .get_or_insert_with(Vec::new)
+            .extend(hashes.iter().map(|h| ApiKey::from_hash(h)));
+        self
+    }
+
+    /// Issue a new API key, the first one turns on API key authentication.
+    /// Record it in the [`IssuedApiKeys`] to keep it when the application
+    /// is rebuilt.
+    pub fn issue(&self) -> IssuedApiKey {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let key = format!("gk_{}", URL_SAFE_NO_PAD.encode(bytes));
+        let api_key = ApiKey::from_hash(&hash_api_key(&key));
+        let issued = IssuedApiKey {
+            id: api_key.id.clone(),
+            key,
+            hash: api_key.hash.clone(),
+        };
+        self.api_keys
+            .write()
+            .unwrap()
+            .get_or_insert_with(Vec::new)
+            .push(api_key);
+        issued
+    }
+
+    /// Revoke the keys with the id, false when there was none
+    pub fn revoke(&self, id: &str) -> bool {
+        let mut api_keys = self.api_keys.write().unwrap();
+        let Some(keys) = api_keys.as_mut() else {
+            return false;
+        };
+        let count = keys.len();
+        keys.retain(|key| key.id != id);
+        keys.len() != count
+    }
+
+    pub fn key_ids(&self) -> Vec<String> {
+        self.api_keys
+            .read()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|key| key.id.clone())
+            .collect()
+    }
+
+    fn verify_api_key(&self, key: Option<&str>) -> Result<(), CredentialError> {
+        let api_keys = self.api_keys.read().unwrap();
+        let Some(keys) = api_keys.as_ref() else {
+            return Ok(());
+        };
+        let hash = hash_api_key(key.ok_or(CredentialError::MissingApiKey)?);
+        if keys.iter().any(|key| key.hash == hash) {
+            Ok(())
+        } else {
+            Err(CredentialError::InvalidApiKey)
+        }
+    }
+
+    /// Check the client certificate, API key and signature of a request
+    /// claiming the application. A signed body is buffered and replayed to the upstream.
+    pub async fn verify(
+        &self,
+        session: &mut Session,
+    ) -> pingora::Result<Result<(), CredentialError>> {
+        if self.client_cert.is_some()
+            && !client_identity(session).is_some_and(|identity| self.matches_client_cert(&identity))
+        {
+            return Ok(Err(CredentialError::InvalidClientCertificate));
+        }
+        if let Err(e) = self.verify_api_key(header(session, GATEWAY_APIKEY)) {
+            return Ok(Err(e));
+        }
+        let Some(hmac) = &self.hmac else {
+            return Ok(Ok(()));
+        };
+        let (Some(timestamp), Some(nonce), Some(signature)) = (
+            header(session, GATEWAY_TIMESTAMP).map(str::to_string),
+            header(session, GATEWAY_NONCE).map(str::to_string),
+            header(session, GATEWAY_SIGNATURE).map(str::to_string),
+        ) else {
+            return Ok(Err(CredentialError::MissingSignature));
+        };
+        let Some(body_hash) = read_body_sha256(session).await? else {
+            return Ok(Err(CredentialError::BodyTooLarge));
+        };
+        let req = session.req_header();
+        let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+        Ok(hmac.verify(
+            req.method.as_str(),
+            path,
+            &timestamp,
+            &nonce,
+            &body_hash,
+            &signature,
+        ))
+    }
+}
+
+/// Hashes of the API keys issued through the admin api by application,
+/// persisted as a JSON file so they survive restarts and application updates.
+pub struct IssuedApiKeys {
+    path: PathBuf,
+    hashes: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl IssuedApiKeys {
+    pub fn load(path: &str) -> Self {
+        let hashes = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                error!("Invalid api key file {}: {:?}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Failed to read api key file {}: {:?}", path, e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path: PathBuf::from(path),
+            hashes: Mutex::new(hashes),
+        }
+    }
+
+    pub fn hashes(&self, app_id: &str) -> Vec<String> {
+        let hashes = self.hashes.lock().unwrap();
+        hashes.get(app_id).cloned().unwrap_or_default()
+    }
+
+    pub fn add(&self, app_id: &str, hash: &str) -> anyhow::Result<()> {
+        let mut hashes = self.hashes.lock().unwrap();
+        hashes
+            .entry(app_id.to_string())
+            .or_default()
+            .push(hash.to_string());
+        self.write(&hashes)
+    }
+
+    /// Forget the keys with the id
+    pub fn revoke(&self, app_id: &str, id: &str) -> anyhow::Result<()> {
+        let mut hashes = self.hashes.lock().unwrap();
+        let Some(keys) = hashes.get_mut(app_id) else {
+            return Ok(());
+        };
+        keys.retain(|hash| ApiKey::from_hash(hash).id != id);
+        if keys.is_empty() {
+            hashes.remove(app_id);
+        }
+        self.write(&hashes)
+    }
+
+    pub fn remove(&self, app_id: &str) -> anyhow::Result<()> {
+        let mut hashes = self.hashes.lock().unwrap();
+        if hashes.remove(app_id).is_none() {
+            return Ok(());
+        }
+        self.write(&hashes)
+    }
+
+    fn write(&self, hashes: &HashMap<String, Vec<String>>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(hashes)?;
+        // replace the file atomically so a crash never leaves it truncated
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data).and_then(|_| std::fs::rename(&tmp, &self.path))?;
+        Ok(())
+    }
+}
+
+fn header<'a>(session: &'a Session, name: &str) -> Option<&'a str> {
+    session.get_header(name).and_then(|v| v.to_str().ok())
+}
+
+/// Read the whole request body into the retry buffer, which the proxy sends
+/// upstream ahead of the rest of the body. `None` when it does not fit.
+async fn read_body_sha256(session: &mut Session) -> pingora::Result<Option<String>> {
+    session.enable_retry_buffering();
+    let mut hasher = Sha256::new();
+    let mut len = 0;
+    while let Some(chunk) = session.read_request_body().await? {
+        len += chunk.len();
+        if len > SIGNED_BODY_LIMIT {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+    }
+    Ok(Some(hex::encode(hasher.finalize())))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_api_key_rotation() {
+        let credentials = Credentials::new(Some(&[]), None);
+        assert_eq!(
+            credentials.verify_api_key(None),
+            Err(CredentialError::MissingApiKey)
+        );
+
+        let old = credentials.issue();
+        let new = credentials.issue();
+        assert!(old.key.starts_with("gk_"));
+        assert_eq!(old.hash, hash_api_key(&old.key));
+        assert!(credentials.verify_api_key(Some(&old.key)).is_ok());
+        assert!(credentials.verify_api_key(Some(&new.key)).is_ok());
+
+        assert!(credentials.revoke(&old.id));
+        assert!(!credentials.revoke(&old.id));
+        assert_eq!(
+            credentials.verify_api_key(Some(&old.key)),
+            Err(CredentialError::InvalidApiKey)
+        );
+        assert!(credentials.verify_api_key(Some(&new.key)).is_ok());
+        assert_eq!(credentials.key_ids(), vec![new.id]);
+
+        assert!(!Credentials::default().required());
+        assert!(Credentials::default().verify_api_key(None).is_ok());
+    }
+
+    #[test]
+    fn test_hmac_signature() {
+        let nonces = Nonces::default();
+        let hmac = HmacVerifier::new("secret", Duration::from_secs(300), nonces.clone());
+        let now = unix_now().to_string();
+        let body_hash = hex::encode(Sha256::digest(b"{}"));
+        let signature = hmac.sign("POST", "/orders?id=1", &now, "n1", b"{}");
+
+        assert!(hmac
+            .verify("POST", "/orders?id=1", &now, "n1", &body_hash, &signature)
+            .is_ok());
+        assert_eq!(
+            hmac.verify("POST", "/orders?id=1", &now, "n1", &body_hash, &signature),
+            Err(CredentialError::ReplayedNonce)
+        );
+        // a rebuilt application still rejects the replay
+        let hmac = HmacVerifier::new("secret", Duration::from_secs(300), nonces);
+        assert_eq!(
+            hmac.verify("POST", "/orders?id=1", &now, "n1", &body_hash, &signature),
+            Err(CredentialError::ReplayedNonce)
+        );
+        assert_eq!(
+            hmac.verify("POST", "/orders?id=2", &now, "n2", &body_hash, &signature),
+            Err(CredentialError::InvalidSignature)
+        );
+
+        let stale = (unix_now() - 600).to_string();
+        let signature = hmac.sign("GET", "/", &stale, "n3", b"");
+        assert_eq!(
+            hmac.verify(
+                "GET",
+                "/",
+                &stale,
+                "n3",
+                &hex::encode(Sha256::digest(b"")),
+                &signature
+            ),
+            Err(CredentialError::InvalidTimestamp)
+        );
+    }
+
+    #[test]
+    fn test_issued_api_keys_persist() {
+        let path = std::env::temp_dir().join(format!("gateway_keys_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let issued = Credentials::default().issue();
+
+        let keys = IssuedApiKeys::load(path);
+        keys.add("app", &issued.hash).unwrap();
+
+        // a rebuilt application takes the keys issued before
+        let keys = IssuedApiKeys::load(path);
+        let credentials = Credentials::default().with_api_keys(&keys.hashes("app"));
+        assert!(credentials.required());
+        assert!(credentials.verify_api_key(Some(&issued.key)).is_ok());
+
+        keys.revoke("app", &issued.id).unwrap();
+        assert!(IssuedApiKeys::load(path).hashes("app").is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
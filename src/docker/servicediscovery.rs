@@ -8,8 +8,10 @@ use pingora::lb::{discovery::ServiceDiscovery, Backend};
 use pingora::prelude::*;
 use tracing::error;
 
+use crate::lb::unix_backend;
 use crate::r#const::{
     DOCKER_LABEL_DOCKER_COMPOSE_SERVICE, DOCKER_LABEL_GATEWAY_HOST_IP, DOCKER_LABEL_GATEWAY_MODE,
+    DOCKER_LABEL_GATEWAY_UNIX_SOCKET,
 };
 use crate::store;
 
@@ -68,6 +70,7 @@ impl DockerServiceDiscovery {
                     host_ip: self.get_host_ip(&c, &docker0_ip),
                     mode: self.get_container_mode(&c),
                     inner_ips: self.get_bridge_ips(&c),
+                    unix_socket: self.get_unix_socket(c),
                 })
             })
             .collect())
@@ -86,6 +89,14 @@ impl DockerServiceDiscovery {
             .unwrap_or(docker0.to_string())
     }
 
+    fn get_unix_socket(&self, container: &ContainerSummary) -> Option<String> {
+        container
+            .labels
+            .as_ref()?
+            .get(DOCKER_LABEL_GATEWAY_UNIX_SOCKET)
+            .cloned()
+    }
+
     fn get_bridge_ips(&self, container: &ContainerSummary) -> Option<HashMap<String, String>> {
         container.network_settings.as_ref().and_then(|ns| {
             let networks = ns.networks.as_ref()?;
@@ -166,6 +177,8 @@ struct Container {
     inner_ips: Option<HashMap<String, String>>,
     mode: ContainerMode,
     ports: Vec<ContainerPort>,
+    /// host path of a unix socket the container listens on
+    unix_socket: Option<String>,
 }
 
 struct ContainerPort {
@@ -181,6 +194,14 @@ impl ServiceDiscovery for DockerServiceDiscovery {
         let containers = self.filter_container_list().await.unwrap();
 
         for container in containers {
+            if let Some(path) = &container.unix_socket {
+                match unix_backend(path) {
+                    Ok(b) => backend.push(b),
+                    Err(e) => error!("Invalid unix socket {}: {:?}", path, e),
+                }
+                continue;
+            }
+
             if let Some(port) = container.ports.get(0) {
                 let ip_prots = match container.mode {
                     ContainerMode::Host => Some(vec![(container.host_ip, port.public_port)]),
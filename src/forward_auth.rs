@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::{header, HeaderName, HeaderValue};
+use bytes::{Bytes, BytesMut};
+use pingora::http::RequestHeader;
+
+use crate::config::GatewayForwardAuthConfig;
+
+const FORWARD_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Drop expired answers once this many are cached
+const ANSWER_CACHE_PRUNE_SIZE: usize = 10_000;
+/// Denial bodies are returned to the client, a larger one fails the check
+const MAX_DENIAL_BODY_SIZE: usize = 64 * 1024;
+/// Headers of a denial that only describe the auth service connection
+const HOP_BY_HOP_HEADERS: [HeaderName; 4] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::CONTENT_LENGTH,
+    header::UPGRADE,
+];
+
+/// The answer of the auth service
+pub struct AuthAnswer {
+    pub status: u16,
+    /// The listed headers of an allowed request, copied upstream, or the
+    /// headers of a denial, returned to the client
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Bytes,
+}
+
+impl AuthAnswer {
+    pub fn allowed(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Delegates the decision on a request to an auth service, like nginx
+/// `auth_request`. The service gets the original method and URI in
+/// `X-Forwarded-Method` and `X-Forwarded-Uri`.
+pub struct ForwardAuth {
+    url: String,
+    request_headers: Vec<HeaderName>,
+    response_headers: Vec<HeaderName>,
+    cache_ttl: Option<Duration>,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, (Instant, Arc<AuthAnswer>)>>,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &GatewayForwardAuthConfig) -> anyhow::Result<Self> {
+        let names = |names: &Option<Vec<String>>| {
+            names
+                .iter()
+                .flatten()
+                .map(|name| HeaderName::try_from(name.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let timeout = config
+            .timeout_ms
+            .map_or(FORWARD_AUTH_TIMEOUT, Duration::from_millis);
+        Ok(Self {
+            url: config.url.to_string(),
+            request_headers: names(&config.request_headers)?,
+            response_headers: names(&config.response_headers)?,
+            cache_ttl: config.cache_ttl_seconds.map(Duration::from_secs),
+            // redirects to a login page are answers for the client
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The headers copied upstream from an allowing answer
+    pub fn response_headers(&self) -> &[HeaderName] {
+        &self.response_headers
+    }
+
+    pub async fn check(
+        &self,
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+    ) -> anyhow::Result<Arc<AuthAnswer>> {
+        let uri = req.uri.path_and_query().map_or("/", |p| p.as_str());
+        let headers = self
+            .request_headers
+            .iter()
+            .filter_map(|name| req.headers.get(name).map(|value| (name, value)))
+            .collect::<Vec<_>>();
+
+        let key = self.cache_ttl.map(|_| {
+            let mut key = format!("{} {} {:?}", req.method, uri, client_ip);
+            for (name, value) in &headers {
+                key.push_str(&format!(
+                    "\n{}: {}",
+                    name,
+                    String::from_utf8_lossy(value.as_bytes())
+                ));
+            }
+            key
+        });
+        if let Some(key) = &key
+            && let Some((expires, answer)) = self.cache.lock().unwrap().get(key)
+            && *expires > Instant::now()
+        {
+            return Ok(answer.clone());
+        }
+
+        let mut request = self
+            .client
+            .get(&self.url)
+            .header("X-Forwarded-Method", req.method.as_str())
+            .header("X-Forwarded-Uri", uri);
+        let host = req
+            .headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or(req.uri.host());
+        if let Some(host) = host {
+            request = request.header("X-Forwarded-Host", host);
+        }
+        if let Some(ip) = client_ip {
+            request = request.header("X-Forwarded-For", ip.to_string());
+        }
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let mut response = request.send().await?;
+
+        let status = response.status().as_u16();
+        let allowed = response.status().is_success();
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| match allowed {
+                true => self.response_headers.contains(name),
+                false => !HOP_BY_HOP_HEADERS.contains(name),
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let mut body = BytesMut::new();
+        while !allowed && let Some(chunk) = response.chunk().await? {
+            anyhow::ensure!(
+                body.len() + chunk.len() <= MAX_DENIAL_BODY_SIZE,
+                "auth service denial body over {} bytes",
+                MAX_DENIAL_BODY_SIZE
+            );
+            body.extend_from_slice(&chunk);
+        }
+        let answer = Arc::new(AuthAnswer {
+            status,
+            headers,
+            body: body.freeze(),
+        });
+
+        // errors of the auth service are not decisions on the request
+        let decided = allowed || (400..500).contains(&status);
+        if decided && let (Some(key), Some(ttl)) = (key, self.cache_ttl) {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.len() >= ANSWER_CACHE_PRUNE_SIZE {
+                let now = Instant::now();
+                cache.retain(|_, (expires, _)| *expires > now);
+            }
+            cache.insert(key, (Instant::now() + ttl, answer.clone()));
+        }
+        Ok(answer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+
+    use super::*;
+
+    /// Allows requests with an Authorization header, denials echo the
+    /// forwarded method and URI. `/unavailable` and `/large` answer with a
+    /// 503 and an oversized denial.
+    async fn serve_auth(calls: Arc<AtomicUsize>) -> std::net::SocketAddr {
+        let unavailable_calls = calls.clone();
+        let app = Router::new()
+            .route(
+                "/unavailable",
+                get(move || async move {
+                    unavailable_calls.fetch_add(1, Ordering::Relaxed);
+                    StatusCode::SERVICE_UNAVAILABLE
+                }),
+            )
+            .route(
+                "/large",
+                get(|| async { (StatusCode::UNAUTHORIZED, "x".repeat(100 * 1024)) }),
+            )
+            .route(
+                "/auth",
+                get(move |headers: HeaderMap| async move {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    if headers.contains_key(header::AUTHORIZATION) {
+                        return [("X-User", "u1"), ("X-Other", "o")].into_response();
+                    }
+                    let body = format!(
+                        "{} {}",
+                        headers["X-Forwarded-Method"].to_str().unwrap(),
+                        headers["X-Forwarded-Uri"].to_str().unwrap()
+                    );
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        [("WWW-Authenticate", "Basic")],
+                        body,
+                    )
+                        .into_response()
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = serve_auth(calls.clone()).await;
+        let auth = ForwardAuth::new(&GatewayForwardAuthConfig {
+            url: format!("http://{}/auth", addr),
+            request_headers: Some(vec!["Authorization".to_string()]),
+            response_headers: Some(vec!["X-User".to_string()]),
+            cache_ttl_seconds: Some(60),
+            timeout_ms: None,
+        })
+        .unwrap();
+
+        let mut req = RequestHeader::build("POST", b"/orders?id=1", None).unwrap();
+        let answer = auth.check(&req, None).await.unwrap();
+        assert_eq!(answer.status, 401);
+        assert_eq!(answer.body, "POST /orders?id=1");
+        assert!(answer
+            .headers
+            .iter()
+            .any(|(name, _)| name == "www-authenticate"));
+
+        req.insert_header(header::AUTHORIZATION, "Bearer t")
+            .unwrap();
+        let answer = auth.check(&req, None).await.unwrap();
+        assert!(answer.allowed());
+        assert_eq!(
+            answer.headers,
+            vec![(
+                HeaderName::from_static("x-user"),
+                HeaderValue::from_static("u1")
+            )]
+        );
+
+        // both answers are cached
+        auth.check(&req, None).await.unwrap();
+        req.remove_header(&header::AUTHORIZATION);
+        auth.check(&req, None).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_auth_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = serve_auth(calls.clone()).await;
+        let auth = |path: &str| {
+            ForwardAuth::new(&GatewayForwardAuthConfig {
+                url: format!("http://{}{}", addr, path),
+                request_headers: None,
+                response_headers: None,
+                cache_ttl_seconds: Some(60),
+                timeout_ms: None,
+            })
+            .unwrap()
+        };
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+
+        // an unavailable auth service is asked again
+        let unavailable = auth("/unavailable");
+        assert_eq!(unavailable.check(&req, None).await.unwrap().status, 503);
+        assert_eq!(unavailable.check(&req, None).await.unwrap().status, 503);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        assert!(auth("/large").check(&req, None).await.is_err());
+    }
+}
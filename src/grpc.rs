@@ -0,0 +1,269 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::http::{self, HeaderValue};
+use bytes::Bytes;
+use pingora::{
+    http::ResponseHeader,
+    lb::{health_check::HealthCheck, Backend},
+    prelude::*,
+    proxy::Session,
+};
+
+use crate::stream::connect_stream;
+
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+const GRPC_HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+const GRPC_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const GRPC_SERVING: u8 = 1;
+
+/// The gRPC status codes the gateway generates itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrpcStatus {
+    Unknown = 2,
+    DeadlineExceeded = 4,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+impl GrpcStatus {
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            401 => GrpcStatus::Unauthenticated,
+            403 => GrpcStatus::PermissionDenied,
+            404 => GrpcStatus::Unimplemented,
+            429 => GrpcStatus::ResourceExhausted,
+            502..=504 => GrpcStatus::Unavailable,
+            _ => GrpcStatus::Unknown,
+        }
+    }
+
+    pub fn from_error(e: &Error) -> Self {
+        match e.etype() {
+            ErrorType::HTTPStatus(code) => Self::from_http_status(*code),
+            ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout => {
+                GrpcStatus::DeadlineExceeded
+            }
+            ErrorType::ConnectNoRoute
+            | ErrorType::ConnectRefused
+            | ErrorType::ConnectError
+            | ErrorType::ConnectionClosed => GrpcStatus::Unavailable,
+            _ => match e.esource() {
+                ErrorSource::Upstream => GrpcStatus::Unavailable,
+                _ => GrpcStatus::Internal,
+            },
+        }
+    }
+}
+
+pub fn is_grpc_request(session: &Session) -> bool {
+    session
+        .get_header(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(GRPC_CONTENT_TYPE))
+}
+
+/// The `grpc-timeout` header of the request, e.g., "100m" or "5S"
+pub fn grpc_timeout(session: &Session) -> Option<Duration> {
+    let value = session.get_header("grpc-timeout")?.to_str().ok()?;
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let amount = amount.parse::<u64>().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Answer with a trailers-only gRPC response carrying the status
+pub async fn respond_grpc_error(
+    session: &mut Session,
+    status: GrpcStatus,
+    message: &str,
+) -> Result<()> {
+    let mut header = ResponseHeader::build(200, Some(3))?;
+    header.insert_header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)?;
+    header.insert_header("grpc-status", (status as u8).to_string())?;
+    header.insert_header("grpc-message", encode_grpc_message(message))?;
+    session.set_keepalive(None);
+    session
+        .write_response_header(Box::new(header), true)
+        .await?;
+    session.finish_body().await
+}
+
+/// Percent-encode a grpc-message as required by the gRPC http2 protocol
+fn encode_grpc_message(message: &str) -> HeaderValue {
+    let mut encoded = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (0x20..0x7f).contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    HeaderValue::from_str(&encoded).unwrap_or(HeaderValue::from_static(""))
+}
+
+/// gRPC health checking protocol, see grpc.health.v1.Health/Check
+pub struct GrpcHealthCheck {
+    service: String,
+    /// Number of successful checks to flip from unhealthy to healthy.
+    pub consecutive_success: usize,
+    /// Number of failed checks to flip from healthy to unhealthy.
+    pub consecutive_failure: usize,
+}
+
+impl GrpcHealthCheck {
+    pub fn new(service: &str) -> Box<Self> {
+        Box::new(Self {
+            service: service.to_string(),
+            consecutive_success: 1,
+            consecutive_failure: 1,
+        })
+    }
+
+    async fn check_serving(&self, target: &Backend) -> anyhow::Result<()> {
+        let stream = connect_stream(&target.addr).await?;
+        let (client, connection) = h2::client::handshake(stream).await?;
+        tokio::spawn(connection);
+
+        let request =
+            http::Request::post(format!("http://{}{}", target.addr, GRPC_HEALTH_CHECK_PATH))
+                .header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)
+                .header(http::header::TE, "trailers")
+                .body(())?;
+        let mut client = client.ready().await?;
+        let (response, mut send) = client.send_request(request, false)?;
+        send.send_data(encode_health_request(&self.service), true)?;
+
+        let (parts, mut body) = response.await?.into_parts();
+        let mut message = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            message.extend_from_slice(&chunk);
+        }
+        // trailers-only responses carry the status in the headers
+        let trailers = body.trailers().await?.unwrap_or(parts.headers);
+        let status = trailers
+            .get("grpc-status")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if status != "0" {
+            anyhow::bail!("grpc health check failed with status {:?}", status);
+        }
+
+        match decode_health_response(&message) {
+            Some(GRPC_SERVING) => Ok(()),
+            status => anyhow::bail!("grpc service not serving: {:?}", status),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for GrpcHealthCheck {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        match tokio::time::timeout(GRPC_HEALTH_CHECK_TIMEOUT, self.check_serving(target)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                Error::e_explain(ErrorType::CustomCode("grpc health check", 1), e.to_string())
+            }
+            Err(_) => Error::e_explain(ErrorType::ConnectTimedout, "grpc health check timeout"),
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+}
+
+/// A length-prefixed `HealthCheckRequest { service }` message
+fn encode_health_request(service: &str) -> Bytes {
+    let mut message = vec![];
+    if !service.is_empty() {
+        message.push(0x0a); // field 1, length delimited
+        let mut len = service.len();
+        while len >= 0x80 {
+            message.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        message.push(len as u8);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut frame = vec![0u8];
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    Bytes::from(frame)
+}
+
+/// The status of a length-prefixed `HealthCheckResponse { status }` message
+fn decode_health_response(frame: &[u8]) -> Option<u8> {
+    let message = frame.get(5..)?;
+    match message {
+        // field 1, varint
+        [0x08, status, ..] => Some(*status),
+        // the default UNKNOWN status is omitted from the wire
+        [] => Some(0),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn serve_health(serving: u8) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut connection = h2::server::handshake(socket).await.unwrap();
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                assert_eq!(request.uri().path(), GRPC_HEALTH_CHECK_PATH);
+                let response = http::Response::builder()
+                    .header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)
+                    .body(())
+                    .unwrap();
+                let mut send = respond.send_response(response, false).unwrap();
+                send.send_data(Bytes::from(vec![0, 0, 0, 0, 2, 0x08, serving]), false)
+                    .unwrap();
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                send.send_trailers(trailers).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_grpc_health_check() {
+        let check = GrpcHealthCheck::new("");
+
+        let serving = Backend::new(&serve_health(GRPC_SERVING).await.to_string()).unwrap();
+        assert!(check.check(&serving).await.is_ok());
+
+        let not_serving = Backend::new(&serve_health(2).await.to_string()).unwrap();
+        assert!(check.check(&not_serving).await.is_err());
+    }
+
+    #[test]
+    fn test_encode_grpc_message() {
+        assert_eq!(encode_grpc_message("no route"), "no route");
+        assert_eq!(encode_grpc_message("100% é"), "100%25 %C3%A9");
+    }
+}
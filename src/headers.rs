@@ -0,0 +1,249 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use axum::http::HeaderName;
+use pingora::http::{RequestHeader, ResponseHeader};
+use regex::Regex;
+use tracing::error;
+
+use crate::config::GatewayHeadersConfig;
+
+/// What a header template may refer to
+pub struct TemplateVars<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub app_id: Option<&'a str>,
+    pub route: &'a str,
+    pub upstream: Option<&'a str>,
+    /// Groups of the route `rewrite` regex by index and name
+    pub captures: &'a HashMap<String, String>,
+}
+
+/// The groups of `regex` in `path` by index and name, empty without a match
+pub fn path_captures(regex: &Regex, path: &str) -> HashMap<String, String> {
+    let Some(captures) = regex.captures(path) else {
+        return HashMap::new();
+    };
+    let mut groups = HashMap::new();
+    for (i, name) in regex.capture_names().enumerate() {
+        if let Some(group) = captures.get(i) {
+            groups.insert(i.to_string(), group.as_str().to_string());
+            if let Some(name) = name {
+                groups.insert(name.to_string(), group.as_str().to_string());
+            }
+        }
+    }
+    groups
+}
+
+enum TemplatePart {
+    Literal(String),
+    ClientIp,
+    AppId,
+    Route,
+    Upstream,
+    Capture(String),
+}
+
+/// A header value or redirect target with `$name` or `${name}` variables,
+/// "$$" is a dollar sign. Unknown names are regex captures, missing values
+/// render empty.
+pub struct HeaderTemplate(Vec<TemplatePart>);
+
+impl HeaderTemplate {
+    pub fn parse(template: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let (name, len) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+                Some((name, _)) => (name, name.len() + 2),
+                None => {
+                    let len = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    (&rest[..len], len)
+                }
+            };
+            if name.is_empty() {
+                if rest.starts_with('$') {
+                    rest = &rest[1..];
+                }
+                literal.push('$');
+                continue;
+            }
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(match name {
+                "client_ip" => TemplatePart::ClientIp,
+                "app_id" => TemplatePart::AppId,
+                "route" => TemplatePart::Route,
+                "upstream" => TemplatePart::Upstream,
+                name => TemplatePart::Capture(name.to_string()),
+            });
+            rest = &rest[len..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Self(parts)
+    }
+
+    pub fn render(&self, vars: &TemplateVars) -> String {
+        let mut value = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(s) => value.push_str(s),
+                TemplatePart::ClientIp => {
+                    if let Some(ip) = vars.client_ip {
+                        value.push_str(&ip.to_canonical().to_string());
+                    }
+                }
+                TemplatePart::AppId => value.push_str(vars.app_id.unwrap_or_default()),
+                TemplatePart::Route => value.push_str(vars.route),
+                TemplatePart::Upstream => value.push_str(vars.upstream.unwrap_or_default()),
+                TemplatePart::Capture(name) => {
+                    value.push_str(vars.captures.get(name).map_or("", |s| s.as_str()))
+                }
+            }
+        }
+        value
+    }
+}
+
+/// The header operations shared by requests and responses
+pub trait HeaderMut {
+    fn remove(&mut self, name: &HeaderName);
+    fn insert(&mut self, name: HeaderName, value: String) -> pingora::Result<()>;
+    fn append(&mut self, name: HeaderName, value: String) -> pingora::Result<()>;
+}
+
+impl HeaderMut for RequestHeader {
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+
+    fn insert(&mut self, name: HeaderName, value: String) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: String) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+}
+
+impl HeaderMut for ResponseHeader {
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+
+    fn insert(&mut self, name: HeaderName, value: String) -> pingora::Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: String) -> pingora::Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+}
+
+/// Headers removed, set and added, in that order
+pub struct HeaderRules {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderTemplate)>,
+    add: Vec<(HeaderName, HeaderTemplate)>,
+}
+
+impl HeaderRules {
+    pub fn new(config: &GatewayHeadersConfig) -> anyhow::Result<Self> {
+        let templates = |headers: &Option<HashMap<String, String>>| {
+            headers
+                .iter()
+                .flatten()
+                .map(|(name, template)| {
+                    Ok((
+                        HeaderName::try_from(name.as_str())?,
+                        HeaderTemplate::parse(template),
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            remove: config
+                .remove
+                .iter()
+                .flatten()
+                .map(|name| HeaderName::try_from(name.as_str()))
+                .collect::<Result<_, _>>()?,
+            set: templates(&config.set)?,
+            add: templates(&config.add)?,
+        })
+    }
+
+    /// Rendered values that are no valid header value are skipped
+    pub fn apply(&self, headers: &mut impl HeaderMut, vars: &TemplateVars) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, template) in &self.set {
+            if let Err(e) = headers.insert(name.clone(), template.render(vars)) {
+                error!("Failed to set header {}: {:?}", name, e);
+            }
+        }
+        for (name, template) in &self.add {
+            if let Err(e) = headers.append(name.clone(), template.render(vars)) {
+                error!("Failed to add header {}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_rules() {
+        let regex = Regex::new("^/api/(?<version>v[0-9]+)/(.*)").unwrap();
+        let captures = path_captures(&regex, "/api/v2/orders");
+        let vars = TemplateVars {
+            client_ip: "::ffff:10.0.0.1".parse().ok(),
+            app_id: Some("app1"),
+            route: "api",
+            upstream: None,
+            captures: &captures,
+        };
+        let render = |template: &str| HeaderTemplate::parse(template).render(&vars);
+        assert_eq!(render("$client_ip"), "10.0.0.1");
+        assert_eq!(render("${app_id}@$route"), "app1@api");
+        assert_eq!(render("$version/$2 $1"), "v2/orders v2");
+        assert_eq!(render("[$upstream] $missing"), "[] ");
+        assert_eq!(render("$$5 $ ${}"), "$5 $ ${}");
+
+        let rules = HeaderRules::new(&GatewayHeadersConfig {
+            set: Some(HashMap::from([(
+                "X-Route".to_string(),
+                "$route".to_string(),
+            )])),
+            add: Some(HashMap::from([("Via".to_string(), "gateway".to_string())])),
+            remove: Some(vec!["X-Internal".to_string(), "X-Route".to_string()]),
+        })
+        .unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("X-Internal", "secret").unwrap();
+        req.insert_header("X-Route", "spoofed").unwrap();
+        req.insert_header("Via", "proxy").unwrap();
+        rules.apply(&mut req, &vars);
+        assert!(req.headers.get("X-Internal").is_none());
+        assert_eq!(req.headers["X-Route"], "api");
+        assert_eq!(req.headers.get_all("Via").iter().count(), 2);
+
+        assert!(HeaderRules::new(&GatewayHeadersConfig {
+            set: None,
+            add: None,
+            remove: Some(vec!["bad header".to_string()]),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::http;
+use jsonwebtoken::{
+    decode, decode_header, errors::ErrorKind, jwk::JwkSet, Algorithm, DecodingKey, Validation,
+};
+use pingora::proxy::Session;
+use serde_json::Value;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::config::GatewayJwtConfig;
+
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+/// Tokens signed by an unknown key refetch the JWKS at most this often
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum JwtError {
+    MissingToken,
+    InvalidToken(jsonwebtoken::errors::Error),
+    UnknownKey,
+    MissingClaim(String),
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::MissingToken => write!(f, "missing bearer token"),
+            JwtError::InvalidToken(e) => write!(f, "invalid token: {}", e),
+            JwtError::UnknownKey => write!(f, "token signed by an unknown key"),
+            JwtError::MissingClaim(claim) => write!(f, "missing claim: {}", claim),
+        }
+    }
+}
+
+struct JwtKey {
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+fn jwk_set_keys(set: &JwkSet) -> Vec<JwtKey> {
+    set.keys
+        .iter()
+        .filter_map(|jwk| match DecodingKey::from_jwk(jwk) {
+            Ok(key) => Some(JwtKey {
+                kid: jwk.common.key_id.clone(),
+                key,
+            }),
+            Err(e) => {
+                warn!("Skipping unsupported jwk {:?}: {}", jwk.common.key_id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Keys of a local file, a JWKS, a PEM public key or an HMAC secret
+fn file_keys(path: &str) -> anyhow::Result<Vec<JwtKey>> {
+    let data = std::fs::read(path)?;
+    let text = String::from_utf8_lossy(&data);
+    let text = text.trim();
+    let key = if text.starts_with('{') {
+        return Ok(jwk_set_keys(&serde_json::from_str(text)?));
+    } else if text.contains("-----BEGIN") {
+        DecodingKey::from_rsa_pem(text.as_bytes())
+            .or_else(|_| DecodingKey::from_ec_pem(text.as_bytes()))?
+    } else {
+        DecodingKey::from_secret(text.as_bytes())
+    };
+    Ok(vec![JwtKey { kid: None, key }])
+}
+
+#[derive(Default)]
+struct JwksCache {
+    keys: Arc<Vec<JwtKey>>,
+    fetched_at: Option<Instant>,
+}
+
+impl JwksCache {
+    fn stale(&self, refresh: Duration, kid: Option<&str>) -> bool {
+        let Some(fetched_at) = self.fetched_at else {
+            return true;
+        };
+        let unknown_kid =
+            kid.is_some_and(|kid| !self.keys.iter().any(|k| k.kid.as_deref() == Some(kid)));
+        fetched_at.elapsed() >= refresh
+            || (unknown_kid && fetched_at.elapsed() >= JWKS_MIN_REFRESH_INTERVAL)
+    }
+}
+
+/// A remote JWKS, the keys are kept when a refresh fails
+struct Jwks {
+    url: String,
+    refresh: Duration,
+    client: reqwest::Client,
+    cache: RwLock<JwksCache>,
+}
+
+impl Jwks {
+    async fn keys(&self, kid: Option<&str>) -> Arc<Vec<JwtKey>> {
+        {
+            let cache = self.cache.read().await;
+            if !cache.stale(self.refresh, kid) {
+                return cache.keys.clone();
+            }
+        }
+        let mut cache = self.cache.write().await;
+        // another request may have refreshed the keys meanwhile
+        if cache.stale(self.refresh, kid) {
+            match self.fetch().await {
+                Ok(keys) => {
+                    info!("Fetched {} keys from {}", keys.len(), self.url);
+                    cache.keys = Arc::new(keys);
+                }
+                Err(e) => warn!("Failed to fetch jwks from {}: {:?}", self.url, e),
+            }
+            cache.fetched_at = Some(Instant::now());
+        }
+        cache.keys.clone()
+    }
+
+    async fn fetch(&self) -> anyhow::Result<Vec<JwtKey>> {
+        let set: JwkSet = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(jwk_set_keys(&set))
+    }
+}
+
+enum JwtKeys {
+    Static(Arc<Vec<JwtKey>>),
+    Jwks(Jwks),
+}
+
+/// Validates the bearer tokens of a route
+pub struct JwtPolicy {
+    validation: Validation,
+    keys: JwtKeys,
+    required_claims: Vec<String>,
+    /// Claim names and the upstream headers they are forwarded in
+    forward_claims: Vec<(String, String)>,
+}
+
+impl JwtPolicy {
+    pub fn new(config: &GatewayJwtConfig) -> anyhow::Result<Self> {
+        let algorithms = match &config.algorithms {
+            Some(algorithms) => algorithms
+                .iter()
+                .map(|alg| Algorithm::from_str(alg))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![Algorithm::RS256],
+        };
+        anyhow::ensure!(!algorithms.is_empty(), "jwt algorithms must not be empty");
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        if let Some(issuers) = &config.issuers {
+            validation.set_issuer(issuers);
+        }
+        match &config.audiences {
+            Some(audiences) => validation.set_audience(audiences),
+            None => validation.validate_aud = false,
+        }
+
+        let keys = match (&config.jwks_url, &config.key_file) {
+            (Some(url), None) => JwtKeys::Jwks(Jwks {
+                url: url.to_string(),
+                refresh: config
+                    .jwks_refresh_seconds
+                    .map_or(JWKS_REFRESH_INTERVAL, Duration::from_secs),
+                client: reqwest::Client::builder().timeout(JWKS_TIMEOUT).build()?,
+                cache: RwLock::new(JwksCache::default()),
+            }),
+            (None, Some(path)) => JwtKeys::Static(Arc::new(file_keys(path)?)),
+            _ => anyhow::bail!("exactly one of jwks_url and key_file is required"),
+        };
+
+        Ok(Self {
+            validation,
+            keys,
+            required_claims: config.required_claims.clone().unwrap_or_default(),
+            forward_claims: config
+                .forward_claims
+                .iter()
+                .flatten()
+                .map(|(claim, header)| (claim.to_string(), header.to_string()))
+                .collect(),
+        })
+    }
+
+    /// The claims of a valid token
+    pub async fn verify(&self, token: Option<&str>) -> Result<Value, JwtError> {
+        let token = token.ok_or(JwtError::MissingToken)?;
+        let header = decode_header(token).map_err(JwtError::InvalidToken)?;
+        let keys = match &self.keys {
+            JwtKeys::Static(keys) => keys.clone(),
+            JwtKeys::Jwks(jwks) => jwks.keys(header.kid.as_deref()).await,
+        };
+        // tokens without a key id are tried against every key
+        let candidates = keys
+            .iter()
+            .filter(|k| header.kid.is_none() || k.kid.is_none() || k.kid == header.kid);
+
+        let mut result = Err(JwtError::UnknownKey);
+        for candidate in candidates {
+            result = decode::<Value>(token, &candidate.key, &self.validation)
+                .map(|data| data.claims)
+                .map_err(JwtError::InvalidToken);
+            match &result {
+                Err(JwtError::InvalidToken(e))
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm
+                    ) =>
+                {
+                    continue;
+                }
+                _ => break,
+            }
+        }
+        let claims = result?;
+
+        if let Some(claim) = self
+            .required_claims
+            .iter()
+            .find(|claim| claims.get(claim.as_str()).is_none_or(Value::is_null))
+        {
+            return Err(JwtError::MissingClaim(claim.to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// The upstream headers carrying the forwarded claims, `None` for claims
+    /// missing from the token so a client cannot supply them itself
+    pub fn claim_headers<'a>(&'a self, claims: &Value) -> Vec<(&'a str, Option<String>)> {
+        self.forward_claims
+            .iter()
+            .map(|(claim, header)| {
+                let value = claims.get(claim).map(|value| match value {
+                    Value::String(s) => s.to_string(),
+                    value => value.to_string(),
+                });
+                (header.as_str(), value)
+            })
+            .collect()
+    }
+}
+
+pub fn bearer_token(session: &Session) -> Option<&str> {
+    session
+        .get_header(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use super::*;
+
+    #[test]
+    fn test_jwt_policy_empty_algorithms() {
+        let config = GatewayJwtConfig {
+            issuers: None,
+            audiences: None,
+            algorithms: Some(vec![]),
+            jwks_url: Some("http://127.0.0.1/jwks".to_string()),
+            key_file: None,
+            jwks_refresh_seconds: None,
+            required_claims: None,
+            forward_claims: None,
+        };
+        assert!(JwtPolicy::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jwt_policy() {
+        let path = std::env::temp_dir().join(format!("gateway_jwt_{}.key", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let config = GatewayJwtConfig {
+            issuers: Some(vec!["https://issuer".to_string()]),
+            audiences: Some(vec!["gateway".to_string()]),
+            algorithms: Some(vec!["HS256".to_string()]),
+            jwks_url: None,
+            key_file: Some(path.to_str().unwrap().to_string()),
+            jwks_refresh_seconds: None,
+            required_claims: Some(vec!["tenant".to_string()]),
+            forward_claims: Some(HashMap::from([(
+                "sub".to_string(),
+                "X-User-Id".to_string(),
+            )])),
+        };
+        let policy = JwtPolicy::new(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let sign = |claims: Value, secret: &[u8]| {
+            encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let claims = serde_json::json!({
+            "iss": "https://issuer", "aud": "gateway", "exp": exp, "sub": "u1", "tenant": "t1"
+        });
+
+        let token = sign(claims.clone(), b"secret");
+        let verified = policy.verify(Some(&token)).await.unwrap();
+        assert_eq!(
+            policy.claim_headers(&verified),
+            vec![("X-User-Id", Some("u1".to_string()))]
+        );
+
+        assert!(matches!(
+            policy.verify(None).await,
+            Err(JwtError::MissingToken)
+        ));
+        let token = sign(claims.clone(), b"other");
+        assert!(matches!(
+            policy.verify(Some(&token)).await,
+            Err(JwtError::InvalidToken(_))
+        ));
+        let mut wrong_aud = claims.clone();
+        wrong_aud["aud"] = "other".into();
+        let token = sign(wrong_aud, b"secret");
+        assert!(matches!(
+            policy.verify(Some(&token)).await,
+            Err(JwtError::InvalidToken(_))
+        ));
+        let mut no_tenant = claims;
+        no_tenant.as_object_mut().unwrap().remove("tenant");
+        let token = sign(no_tenant, b"secret");
+        assert!(matches!(
+            policy.verify(Some(&token)).await,
+            Err(JwtError::MissingClaim(_))
+        ));
+    }
+}
@@ -1,21 +1,90 @@
 use std::sync::Arc;
 
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::ToSocketAddrs,
+    time::Duration,
+};
+
 use async_trait::async_trait;
 use pingora::{
-    lb::{discovery::ServiceDiscovery, Backends, LoadBalancer},
+    lb::{
+        discovery::{ServiceDiscovery, Static},
+        Backend, Backends, Extensions, LoadBalancer,
+    },
     prelude::*,
+    protocols::l4::socket::SocketAddr,
     server::ShutdownWatch,
     services::background::BackgroundService,
 };
 use regex::Regex;
 
+use crate::{
+    action::RouteAction,
+    cache::RouteCache,
+    compression::Compression,
+    cors::CorsPolicy,
+    forward_auth::ForwardAuth,
+    grpc::GrpcHealthCheck,
+    headers::{path_captures, HeaderRules},
+    jwt::JwtPolicy,
+    proxy_protocol::{ProxyProtocolAcceptor, ProxyProtocolVersion},
+    r#const::UNIX_SOCKET_PREFIX,
+    rate_limit::RateLimitPolicy,
+    rewrite::UpstreamRewrite,
+};
+
 pub type PingoraServiceDiscovery = Box<dyn ServiceDiscovery + Send + Sync + 'static>;
 
+/// Static service discovery of "host:port" and "unix:/path/to.sock" upstreams
+pub fn static_discovery(upstreams: &[String]) -> anyhow::Result<PingoraServiceDiscovery> {
+    let mut backends = BTreeSet::new();
+    for upstream in upstreams {
+        match upstream.strip_prefix(UNIX_SOCKET_PREFIX) {
+            Some(path) => {
+                backends.insert(unix_backend(path)?);
+            }
+            None => {
+                backends.extend(upstream.to_socket_addrs()?.map(|addr| Backend {
+                    addr: SocketAddr::Inet(addr),
+                    weight: 1,
+                    ext: Extensions::new(),
+                }));
+            }
+        }
+    }
+    Ok(Static::new(backends))
+}
+
+pub fn unix_backend(path: &str) -> anyhow::Result<Backend> {
+    Ok(Backend {
+        addr: SocketAddr::Unix(std::os::unix::net::SocketAddr::from_pathname(path)?),
+        weight: 1,
+        ext: Extensions::new(),
+    })
+}
+
 pub struct GatewayLoadBalancerOptions {
     pub match_rule: GatewayMatchRule,
     pub service_discovery: PingoraServiceDiscovery,
     pub health_check: bool,
     pub rewrite: Option<(Regex, String)>,
+    pub upstream_rewrite: Option<UpstreamRewrite>,
+    pub listen: Option<String>,
+    pub proxy_protocol: Option<Arc<ProxyProtocolAcceptor>>,
+    pub upstream_proxy_protocol: Option<ProxyProtocolVersion>,
+    pub streaming: Option<GatewayStreaming>,
+    pub grpc: Option<GatewayGrpc>,
+    pub rate_limit: Option<RateLimitPolicy>,
+    pub jwt: Option<Arc<JwtPolicy>>,
+    pub require_application: bool,
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    pub request_headers: Option<HeaderRules>,
+    pub response_headers: Option<HeaderRules>,
+    pub action: Option<RouteAction>,
+    pub compression: Option<Compression>,
+    pub cors: Option<CorsPolicy>,
+    pub cache: Option<RouteCache>,
 }
 
 impl GatewayLoadBalancerOptions {
@@ -29,6 +98,22 @@ impl GatewayLoadBalancerOptions {
             service_discovery,
             health_check,
             rewrite: None,
+            upstream_rewrite: None,
+            listen: None,
+            proxy_protocol: None,
+            upstream_proxy_protocol: None,
+            streaming: None,
+            grpc: None,
+            rate_limit: None,
+            jwt: None,
+            require_application: false,
+            forward_auth: None,
+            request_headers: None,
+            response_headers: None,
+            action: None,
+            compression: None,
+            cors: None,
+            cache: None,
         }
     }
 
@@ -36,12 +121,113 @@ impl GatewayLoadBalancerOptions {
         self.rewrite = Some((regex, replacement));
         self
     }
+
+    pub fn with_upstream_rewrite(mut self, rewrite: UpstreamRewrite) -> Self {
+        self.upstream_rewrite = Some(rewrite);
+        self
+    }
+
+    pub fn with_listen(mut self, listen: String) -> Self {
+        self.listen = Some(listen);
+        self
+    }
+
+    pub fn with_proxy_protocol(mut self, acceptor: ProxyProtocolAcceptor) -> Self {
+        self.proxy_protocol = Some(Arc::new(acceptor));
+        self
+    }
+
+    pub fn with_upstream_proxy_protocol(mut self, version: ProxyProtocolVersion) -> Self {
+        self.upstream_proxy_protocol = Some(version);
+        self
+    }
+
+    pub fn with_streaming(mut self, streaming: GatewayStreaming) -> Self {
+        self.streaming = Some(streaming);
+        self
+    }
+
+    pub fn with_grpc(mut self, grpc: GatewayGrpc) -> Self {
+        self.grpc = Some(grpc);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitPolicy) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_jwt(mut self, jwt: JwtPolicy) -> Self {
+        self.jwt = Some(Arc::new(jwt));
+        self
+    }
+
+    pub fn with_require_application(mut self, require_application: bool) -> Self {
+        self.require_application = require_application;
+        self
+    }
+
+    pub fn with_forward_auth(mut self, forward_auth: ForwardAuth) -> Self {
+        self.forward_auth = Some(Arc::new(forward_auth));
+        self
+    }
+
+    pub fn with_request_headers(mut self, rules: HeaderRules) -> Self {
+        self.request_headers = Some(rules);
+        self
+    }
+
+    pub fn with_response_headers(mut self, rules: HeaderRules) -> Self {
+        self.response_headers = Some(rules);
+        self
+    }
+
+    pub fn with_action(mut self, action: RouteAction) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: RouteCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_health_check(mut self, health_check: bool) -> Self {
+        self.health_check = health_check;
+        self
+    }
 }
 
 pub struct GatewayLoadBalancer {
     name: String,
     match_rule: GatewayMatchRule,
     rewrite: Option<(Regex, String)>,
+    upstream_rewrite: Option<UpstreamRewrite>,
+    listen: Option<String>,
+    proxy_protocol: Option<Arc<ProxyProtocolAcceptor>>,
+    upstream_proxy_protocol: Option<ProxyProtocolVersion>,
+    streaming: Option<GatewayStreaming>,
+    grpc: Option<GatewayGrpc>,
+    rate_limit: Option<RateLimitPolicy>,
+    jwt: Option<Arc<JwtPolicy>>,
+    require_application: bool,
+    forward_auth: Option<Arc<ForwardAuth>>,
+    request_headers: Option<HeaderRules>,
+    response_headers: Option<HeaderRules>,
+    action: Option<RouteAction>,
+    compression: Option<Compression>,
+    cors: Option<CorsPolicy>,
+    cache: Option<RouteCache>,
     inner: Arc<LoadBalancer<RoundRobin>>,
 }
 
@@ -51,7 +237,12 @@ impl GatewayLoadBalancer {
         let mut upstreams = LoadBalancer::from_backends(backends);
 
         if options.health_check {
-            upstreams.set_health_check(TcpHealthCheck::new());
+            match &options.grpc {
+                Some(grpc) => {
+                    upstreams.set_health_check(GrpcHealthCheck::new(&grpc.health_service))
+                }
+                None => upstreams.set_health_check(TcpHealthCheck::new()),
+            }
             upstreams.health_check_frequency = Some(std::time::Duration::from_secs(1));
         }
 
@@ -61,6 +252,22 @@ impl GatewayLoadBalancer {
             match_rule: options.match_rule,
             inner: Arc::new(upstreams),
             rewrite: options.rewrite,
+            upstream_rewrite: options.upstream_rewrite,
+            listen: options.listen,
+            proxy_protocol: options.proxy_protocol,
+            upstream_proxy_protocol: options.upstream_proxy_protocol,
+            streaming: options.streaming,
+            grpc: options.grpc,
+            rate_limit: options.rate_limit,
+            jwt: options.jwt,
+            require_application: options.require_application,
+            forward_auth: options.forward_auth,
+            request_headers: options.request_headers,
+            response_headers: options.response_headers,
+            action: options.action,
+            compression: options.compression,
+            cors: options.cors,
+            cache: options.cache,
         }
     }
 
@@ -72,6 +279,92 @@ impl GatewayLoadBalancer {
         self.match_rule.matches_path(path)
     }
 
+    pub fn matches_sni(&self, sni: Option<&str>) -> bool {
+        self.match_rule.matches_sni(sni)
+    }
+
+    /// The listen address of a tcp route, `None` for http routes
+    pub fn listen(&self) -> Option<&str> {
+        self.listen.as_deref()
+    }
+
+    pub fn proxy_protocol(&self) -> Option<Arc<ProxyProtocolAcceptor>> {
+        self.proxy_protocol.clone()
+    }
+
+    pub fn upstream_proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        self.upstream_proxy_protocol
+    }
+
+    pub fn streaming(&self) -> Option<&GatewayStreaming> {
+        self.streaming.as_ref()
+    }
+
+    pub fn grpc(&self) -> Option<&GatewayGrpc> {
+        self.grpc.as_ref()
+    }
+
+    pub fn rate_limit(&self) -> Option<&RateLimitPolicy> {
+        self.rate_limit.as_ref()
+    }
+
+    pub fn jwt(&self) -> Option<Arc<JwtPolicy>> {
+        self.jwt.clone()
+    }
+
+    /// Whether only requests of known applications are served
+    pub fn require_application(&self) -> bool {
+        self.require_application
+    }
+
+    pub fn forward_auth(&self) -> Option<Arc<ForwardAuth>> {
+        self.forward_auth.clone()
+    }
+
+    pub fn upstream_rewrite(&self) -> Option<&UpstreamRewrite> {
+        self.upstream_rewrite.as_ref()
+    }
+
+    pub fn request_headers(&self) -> Option<&HeaderRules> {
+        self.request_headers.as_ref()
+    }
+
+    pub fn response_headers(&self) -> Option<&HeaderRules> {
+        self.response_headers.as_ref()
+    }
+
+    /// The answer of a route served without an upstream
+    pub fn action(&self) -> Option<&RouteAction> {
+        self.action.as_ref()
+    }
+
+    pub fn compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    pub fn cors(&self) -> Option<&CorsPolicy> {
+        self.cors.as_ref()
+    }
+
+    pub fn cache(&self) -> Option<&RouteCache> {
+        self.cache.as_ref()
+    }
+
+    /// Whether templates are rendered for the route
+    pub fn uses_templates(&self) -> bool {
+        self.request_headers.is_some()
+            || self.response_headers.is_some()
+            || matches!(self.action, Some(RouteAction::Redirect { .. }))
+    }
+
+    /// The groups of the rewrite regex in the path, for templates
+    pub fn path_captures(&self, path: &str) -> HashMap<String, String> {
+        match &self.rewrite {
+            Some((regex, _)) => path_captures(regex, path),
+            None => HashMap::new(),
+        }
+    }
+
     pub fn lb(&self) -> Arc<LoadBalancer<RoundRobin>> {
         self.inner.clone()
     }
@@ -93,10 +386,25 @@ impl BackgroundService for GatewayLoadBalancer {
     }
 }
 
+pub struct GatewayStreaming {
+    pub idle_timeout: Option<Duration>,
+    pub stream_idle_timeout: Option<Duration>,
+}
+
+pub struct GatewayGrpc {
+    /// The service name sent in grpc.health.v1 checks, empty for the server
+    pub health_service: String,
+}
+
 pub enum GatewayMatchRule {
     PathStartsWith(String),
     #[allow(dead_code)]
     PathRegex(Regex),
+    /// TLS server name, a leading "*." matches any subdomain
+    Sni(String),
+    /// gRPC "package.Service" or "package.Service/Method"
+    GrpcMethod(String),
+    Any,
 }
 
 impl GatewayMatchRule {
@@ -104,6 +412,30 @@ impl GatewayMatchRule {
         match self {
             GatewayMatchRule::PathStartsWith(prefix) => path.starts_with(prefix),
             GatewayMatchRule::PathRegex(regex) => regex.is_match(path),
+            GatewayMatchRule::Sni(_) => false,
+            GatewayMatchRule::GrpcMethod(method) => match path
+                .strip_prefix('/')
+                .and_then(|path| path.strip_prefix(method.as_str()))
+            {
+                Some(rest) if method.contains('/') => rest.is_empty(),
+                Some(rest) => rest.starts_with('/'),
+                None => false,
+            },
+            GatewayMatchRule::Any => true,
+        }
+    }
+
+    pub fn matches_sni(&self, sni: Option<&str>) -> bool {
+        match self {
+            GatewayMatchRule::Sni(pattern) => match (sni, pattern.strip_prefix("*.")) {
+                (Some(sni), Some(suffix)) => sni
+                    .strip_suffix(suffix)
+                    .is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+                (Some(sni), None) => sni.eq_ignore_ascii_case(pattern),
+                (None, _) => false,
+            },
+            GatewayMatchRule::Any => true,
+            _ => false,
         }
     }
 }
@@ -123,4 +455,48 @@ mod test {
         assert!(rule_starts_with.matches_path(path1));
         assert!(rule_regex.matches_path(path2));
     }
+
+    #[tokio::test]
+    async fn test_static_discovery() {
+        let upstreams = vec![
+            "127.0.0.1:8080".to_string(),
+            "unix:/run/app.sock".to_string(),
+        ];
+        let (backends, _) = static_discovery(&upstreams)
+            .unwrap()
+            .discover()
+            .await
+            .unwrap();
+
+        assert_eq!(backends.len(), 2);
+        assert!(backends.iter().any(|b| {
+            b.addr.as_unix().is_some_and(|addr| {
+                addr.as_pathname() == Some(std::path::Path::new("/run/app.sock"))
+            })
+        }));
+    }
+
+    #[test]
+    fn test_gateway_match_rule_sni() {
+        let rule_exact = GatewayMatchRule::Sni("db.example.com".to_string());
+        let rule_wildcard = GatewayMatchRule::Sni("*.example.com".to_string());
+
+        assert!(rule_exact.matches_sni(Some("DB.example.com")));
+        assert!(!rule_exact.matches_sni(None));
+        assert!(rule_wildcard.matches_sni(Some("mqtt.example.com")));
+        assert!(!rule_wildcard.matches_sni(Some("example.com")));
+        assert!(GatewayMatchRule::Any.matches_sni(None));
+        assert!(!rule_exact.matches_path("/db"));
+    }
+
+    #[test]
+    fn test_gateway_match_rule_grpc() {
+        let rule_service = GatewayMatchRule::GrpcMethod("helloworld.Greeter".to_string());
+        let rule_method = GatewayMatchRule::GrpcMethod("helloworld.Greeter/SayHello".to_string());
+
+        assert!(rule_service.matches_path("/helloworld.Greeter/SayHello"));
+        assert!(!rule_service.matches_path("/helloworld.GreeterV2/SayHello"));
+        assert!(rule_method.matches_path("/helloworld.Greeter/SayHello"));
+        assert!(!rule_method.matches_path("/helloworld.Greeter/SayHelloAgain"));
+    }
 }
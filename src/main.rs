@@ -1,20 +1,39 @@
+use acl::IpAccessScope;
 use admin::service::AdminService;
 use app::{Application, BackgroundServer, LbInfo};
-use pingora::{proxy::http_proxy_service, server::Server};
+use pingora::{apps::HttpServerOptions, proxy::http_proxy_service, server::Server};
 use proxy::GatewayProxy;
+use r#const::{GATEWAY_INTERNAL_LISTEN, UNIX_SOCKET_PREFIX};
 use service::{GlobalBackgroundService, ProxyService};
 use tracing::{info, Level};
 
+mod acl;
+mod action;
 mod admin;
 mod app;
+mod cache;
+mod compression;
 mod config;
 mod r#const;
+mod cors;
+mod credentials;
 mod docker;
+mod forward_auth;
+mod grpc;
+mod headers;
+mod jwt;
 mod lb;
+mod metrics;
+mod mtls;
 mod proxy;
+mod proxy_protocol;
+mod quota;
 mod rate_limit;
+mod rewrite;
 mod service;
+mod static_files;
 mod store;
+mod stream;
 
 fn main() {
     let config_path = std::env::args()
@@ -28,6 +47,13 @@ fn main() {
     // Initialize the global configuration
     store::init_config(config);
 
+    // unidentified callers would go unlimited without a valid default
+    if let Some(rate_limit) = &store::config().default_rate_limit
+        && let Err(e) = app::default_rate_limit_policy(rate_limit)
+    {
+        panic!("Invalid default rate limit: {:?}", e);
+    }
+
     let trace = tracing_subscriber::fmt()
         .compact()
         .with_max_level(Level::INFO)
@@ -44,9 +70,47 @@ fn main() {
     my_server.add_service(AdminService::new());
 
     let mut proxy_service = http_proxy_service(&my_server.configuration, GatewayProxy::new());
-    proxy_service.add_tcp("0.0.0.0:6188");
+    // plaintext grpc clients use http2 with prior knowledge
+    let mut server_options = HttpServerOptions::default();
+    server_options.h2c = true;
+    if let Some(app) = proxy_service.app_logic_mut() {
+        app.server_options = Some(server_options);
+    }
+    let listeners = store::config().listeners();
+    // only plain tcp listeners terminate tls, proxy protocol listeners forward
+    // to the plaintext internal address
+    for listener in listeners.iter().filter(|l| l.tls.is_some()) {
+        if listener.proxy_protocol.is_some() {
+            panic!(
+                "Listener {} cannot combine tls with proxy_protocol",
+                listener.address
+            );
+        }
+        if listener.address.starts_with(UNIX_SOCKET_PREFIX) {
+            panic!("Tls is not supported on unix listener {}", listener.address);
+        }
+    }
+    for listener in listeners.iter().filter(|l| l.proxy_protocol.is_none()) {
+        match (
+            listener.address.strip_prefix(UNIX_SOCKET_PREFIX),
+            &listener.tls,
+        ) {
+            (Some(path), _) => proxy_service.add_uds(path, None),
+            (None, Some(tls)) => {
+                let settings = mtls::tls_settings(tls).unwrap_or_else(|e| {
+                    panic!("Invalid tls of listener {}: {:?}", listener.address, e);
+                });
+                proxy_service.add_tls_with_settings(&listener.address, None, settings);
+            }
+            (None, None) => proxy_service.add_tcp(&listener.address),
+        }
+        info!("Starting Pingora server with address: {}", listener.address);
+    }
+    // proxy protocol listeners forward to the internal address
+    if listeners.iter().any(|l| l.proxy_protocol.is_some()) {
+        proxy_service.add_tcp(GATEWAY_INTERNAL_LISTEN);
+    }
     my_server.add_service(proxy_service);
-    info!("Starting Pingora server with port: 6188");
 
     // init with tokio runtime
     run_with_tokio_runtime();
@@ -66,12 +130,23 @@ fn run_with_tokio_runtime() {
 }
 
 async fn init_config_to_proxy(config: &config::GatewayConfig) {
+    if let Some(ip_access) = &config.ip_access {
+        app::set_ip_access(IpAccessScope::Global, ip_access);
+    }
+    if let Some(ip_access) = &config.admin_ip_access {
+        app::set_ip_access(IpAccessScope::Route("admin".to_string()), ip_access);
+    }
+
     if let Some(bg) = &config.backgrounds {
         for b in bg {
             app::start_background_service(BackgroundServer::from(b.to_string())).await;
         }
     }
 
+    for listener in config.listeners() {
+        app::start_proxy_protocol_listener(&listener).await;
+    }
+
     if let Some(applications) = &config.applications {
         for app in applications {
             app::add_application(Application::from(app)).await;
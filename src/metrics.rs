@@ -0,0 +1,63 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+/// Open long-lived connections per route, labeled by kind: "websocket", "sse" or "tcp"
+pub static STREAMING_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "gateway_streaming_connections",
+        "Open long-lived connections per route",
+        &["route", "kind"]
+    )
+    .unwrap()
+});
+
+/// Access control decisions per route and application, labeled by decision:
+/// "allowed", "route_denied", "method_denied", "unknown_application" or "ip_denied"
+pub static ACCESS_DECISIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "gateway_access_decisions_total",
+        "Access control decisions per route and application",
+        &["route", "app_id", "decision"]
+    )
+    .unwrap()
+});
+
+/// Render all registered metrics in the prometheus text format
+pub fn gather() -> String {
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {:?}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Keeps a connection counted in [STREAMING_CONNECTIONS] until dropped
+pub struct StreamingGuard {
+    route: String,
+    kind: &'static str,
+}
+
+impl StreamingGuard {
+    pub fn new(route: &str, kind: &'static str) -> Self {
+        STREAMING_CONNECTIONS
+            .with_label_values(&[route, kind])
+            .inc();
+        Self {
+            route: route.to_string(),
+            kind,
+        }
+    }
+}
+
+impl Drop for StreamingGuard {
+    fn drop(&mut self) {
+        STREAMING_CONNECTIONS
+            .with_label_values(&[&self.route, self.kind])
+            .dec();
+    }
+}
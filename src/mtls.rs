@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use openssl::{
+    hash::MessageDigest,
+    ssl::{SslOptions, SslSessionCacheMode, SslVerifyMode},
+    x509::{X509Name, X509Ref},
+};
+use pingora::{listeners::tls::TlsSettings, proxy::Session};
+
+use crate::config::{GatewayClientCertConfig, GatewayTlsConfig};
+
+/// Forget certificates not seen for a while once this many are tracked
+const CLIENT_CERT_PRUNE_SIZE: usize = 10_000;
+const CLIENT_CERT_IDLE: Duration = Duration::from_secs(600);
+
+/// The names of a verified client certificate
+#[derive(Debug)]
+pub struct ClientIdentity {
+    /// Lower-case hex SHA-256 of the certificate
+    pub fingerprint: String,
+    /// Short attribute names and values, e.g., ("CN", "partner")
+    pub subject: Vec<(String, String)>,
+    pub sans: Vec<String>,
+}
+
+impl ClientIdentity {
+    fn from_cert(cert: &X509Ref, digest: &[u8]) -> Self {
+        let subject = cert
+            .subject_name()
+            .entries()
+            .filter_map(|entry| {
+                let name = entry.object().nid().short_name().ok()?;
+                Some((name.to_string(), entry.data().to_string().ok()?))
+            })
+            .collect();
+        let sans = cert
+            .subject_alt_names()
+            .iter()
+            .flatten()
+            .filter_map(|name| {
+                name.dnsname()
+                    .or(name.email())
+                    .or(name.uri())
+                    .map(str::to_string)
+                    .or_else(|| name.ipaddress().and_then(ip_string))
+            })
+            .collect();
+        Self {
+            fingerprint: hex::encode(digest),
+            subject,
+            sans,
+        }
+    }
+}
+
+fn ip_string(bytes: &[u8]) -> Option<String> {
+    let ip = match bytes.len() {
+        4 => std::net::IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+        16 => std::net::IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+        _ => return None,
+    };
+    Some(ip.to_string())
+}
+
+/// Client certificates verified in TLS handshakes by their SHA-256 digest.
+/// The handshake digest of a connection only keeps the digest, this keeps
+/// the names the applications are bound to.
+#[derive(Default)]
+pub struct ClientCertificates(Mutex<HashMap<Vec<u8>, LastSeen>>);
+
+/// When a certificate was last used and its identity
+type LastSeen = (Instant, Arc<ClientIdentity>);
+
+impl ClientCertificates {
+    fn remember(&self, cert: &X509Ref) {
+        let Ok(digest) = cert.digest(MessageDigest::sha256()) else {
+            return;
+        };
+        let mut certs = self.0.lock().unwrap();
+        if certs.len() >= CLIENT_CERT_PRUNE_SIZE {
+            certs.retain(|_, (seen, _)| seen.elapsed() < CLIENT_CERT_IDLE);
+        }
+        let identity = Arc::new(ClientIdentity::from_cert(cert, &digest));
+        certs.insert(digest.to_vec(), (Instant::now(), identity));
+    }
+
+    pub fn identity(&self, digest: &[u8]) -> Option<Arc<ClientIdentity>> {
+        let mut certs = self.0.lock().unwrap();
+        let (seen, identity) = certs.get_mut(digest)?;
+        *seen = Instant::now();
+        Some(identity.clone())
+    }
+}
+
+/// The identity of the verified client certificate of the connection
+pub fn client_identity(session: &Session) -> Option<Arc<ClientIdentity>> {
+    let ssl = session.digest()?.ssl_digest.as_ref()?;
+    if ssl.cert_digest.is_empty() {
+        return None;
+    }
+    crate::store::client_certificates().identity(&ssl.cert_digest)
+}
+
+/// The client certificates an application is bound to
+pub struct ClientCertBinding {
+    subject: Vec<(String, String)>,
+    san: Option<String>,
+    fingerprint: Option<String>,
+}
+
+impl ClientCertBinding {
+    pub fn new(config: &GatewayClientCertConfig) -> anyhow::Result<Self> {
+        let subject = config
+            .subject
+            .iter()
+            .flat_map(|subject| subject.split(','))
+            .map(|attribute| match attribute.split_once('=') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                None => anyhow::bail!("invalid subject attribute: {}", attribute),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let fingerprint = config
+            .fingerprint
+            .as_ref()
+            .map(|f| f.replace(':', "").to_ascii_lowercase());
+        if subject.is_empty() && config.san.is_none() && fingerprint.is_none() {
+            anyhow::bail!("one of subject, san and fingerprint is required");
+        }
+        Ok(Self {
+            subject,
+            san: config.san.clone(),
+            fingerprint,
+        })
+    }
+
+    pub fn matches(&self, identity: &ClientIdentity) -> bool {
+        self.subject
+            .iter()
+            .all(|attribute| identity.subject.contains(attribute))
+            && self
+                .san
+                .as_ref()
+                .is_none_or(|san| identity.sans.contains(san))
+            && self
+                .fingerprint
+                .as_ref()
+                .is_none_or(|fingerprint| *fingerprint == identity.fingerprint)
+    }
+}
+
+/// The TLS settings of a listener. Client certificates are verified against
+/// `client_ca` and remembered for the applications bound to them.
+pub fn tls_settings(config: &GatewayTlsConfig) -> anyhow::Result<TlsSettings> {
+    let mut settings = TlsSettings::intermediate(&config.cert, &config.key)?;
+    settings.enable_h2();
+    let mode = match config.client_auth.as_deref().unwrap_or("none") {
+        "none" => return Ok(settings),
+        "request" => SslVerifyMode::PEER,
+        "require" => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        other => anyhow::bail!("unknown client_auth: {}", other),
+    };
+    let Some(client_ca) = &config.client_ca else {
+        anyhow::bail!("client_auth needs a client_ca");
+    };
+    settings.set_ca_file(client_ca)?;
+    settings.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+    settings.set_verify_callback(mode, |verified, ctx| {
+        if verified
+            && ctx.error_depth() == 0
+            && let Some(cert) = ctx.current_cert()
+        {
+            crate::store::client_certificates().remember(cert);
+        }
+        verified
+    });
+    // resumed sessions skip the verification which remembers the certificate
+    settings.set_session_cache_mode(SslSessionCacheMode::OFF);
+    settings.set_options(SslOptions::NO_TICKET);
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::{
+        asn1::Asn1Time,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+    };
+
+    use super::*;
+
+    fn client_cert() -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("O", "Acme").unwrap();
+        name.append_entry_by_text("CN", "partner").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("partner.example.com")
+            .ip("10.0.0.1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        cert.build()
+    }
+
+    #[test]
+    fn test_client_cert_binding() {
+        let cert = client_cert();
+        let certs = ClientCertificates::default();
+        certs.remember(&cert);
+        let digest = cert.digest(MessageDigest::sha256()).unwrap();
+        let identity = certs.identity(&digest).unwrap();
+        assert_eq!(identity.sans, vec!["partner.example.com", "10.0.0.1"]);
+
+        let binding = |subject: Option<&str>, san: Option<&str>, fingerprint: Option<String>| {
+            ClientCertBinding::new(&GatewayClientCertConfig {
+                subject: subject.map(str::to_string),
+                san: san.map(str::to_string),
+                fingerprint,
+            })
+        };
+        let colons = digest
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(binding(None, None, Some(colons))
+            .unwrap()
+            .matches(&identity));
+        assert!(binding(Some("CN=partner, O=Acme"), None, None)
+            .unwrap()
+            .matches(&identity));
+        assert!(binding(Some("O=Acme"), Some("10.0.0.1"), None)
+            .unwrap()
+            .matches(&identity));
+        assert!(!binding(Some("CN=other,O=Acme"), None, None)
+            .unwrap()
+            .matches(&identity));
+        assert!(!binding(Some("O=Acme"), Some("other.example.com"), None)
+            .unwrap()
+            .matches(&identity));
+        assert!(binding(None, None, None).is_err());
+        assert!(binding(Some("Acme"), None, None).is_err());
+    }
+}
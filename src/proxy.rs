@@ -1,26 +1,93 @@
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use axum::http::{uri::PathAndQuery, Uri};
+use axum::http::{self, uri::PathAndQuery, Uri};
+use bytes::Bytes;
 use pingora::{
-    http::ResponseHeader,
+    cache::{key::HashBinary, CacheKey, CacheMeta, NoCacheReason, RespCacheable},
+    http::{RequestHeader, ResponseHeader},
     prelude::*,
-    proxy::{ProxyHttp, Session},
+    protocols::l4::socket::SocketAddr,
+    proxy::{FailToProxy, ProxyHttp, Session},
 };
-use tracing::{error, info};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{debug, error, info};
 
 use crate::{
-    lb::GatewayLoadBalancerOptions,
-    r#const::{GATEWAY_APPID, GATEWAY_HEADER_EXT, GATEWAY_QUERY_EXT},
+    acl::{AccessDecision, IpAccessScope},
+    action::ActionResponse,
+    compression::Encoder,
+    cors::is_preflight,
+    forward_auth::AuthAnswer,
+    grpc::{grpc_timeout, is_grpc_request, respond_grpc_error, GrpcStatus},
+    headers::TemplateVars,
+    jwt::bearer_token,
+    lb::{GatewayLoadBalancer, GatewayLoadBalancerOptions},
+    metrics::{self, StreamingGuard},
+    mtls::client_identity,
+    proxy_protocol::{real_client_header, ProxyHeader, ProxyProtocolConnector},
+    quota::{Quota, QuotaStore, QuotaUsage},
+    r#const::{
+        GATEWAY_APIKEY, GATEWAY_APPID, GATEWAY_CACHE_STATUS, GATEWAY_HEADER_EXT, GATEWAY_NONCE,
+        GATEWAY_QUERY_EXT, GATEWAY_SIGNATURE, GATEWAY_TIMESTAMP,
+    },
+    rate_limit::{RateLimitHeaderStyle, RateLimitStatus, RateLimitUsage, RateLimiter},
 };
 
+const EVENT_STREAM: &str = "text/event-stream";
+
 pub enum ProxyCmd {
-    Add(String, GatewayLoadBalancerOptions),
+    Add(String, Box<GatewayLoadBalancerOptions>),
     Remove(String),
 }
 
 pub struct GatewayProxy;
 
+#[derive(Default)]
+pub struct GatewayContext {
+    /// The real client and destination address, taken from the PROXY
+    /// protocol header when the connection came through a trusted balancer
+    pub client: ProxyHeader,
+    /// The route selected for the request
+    pub route: Option<Arc<GatewayLoadBalancer>>,
+    /// Set while the response is an upgraded websocket or an event stream,
+    /// body filters must pass these through unbuffered
+    pub streaming: Option<StreamingGuard>,
+    /// The rate limit closest to running out, reported in the response
+    pub rate_limit: Option<RateLimitStatus>,
+    /// The in-flight slot of the application, released when the request
+    /// ends or fails and the context is dropped
+    pub concurrency: Option<OwnedSemaphorePermit>,
+    /// The known application of the request, once its credentials passed
+    pub app_id: Option<String>,
+    /// The address of the selected upstream
+    pub upstream: Option<String>,
+    /// The groups of the route rewrite regex, for templates
+    pub path_captures: HashMap<String, String>,
+    /// Compresses or decompresses the response body for the client
+    pub encoder: Option<Encoder>,
+    /// The claims of the JWT verified by the route's jwt policy
+    pub claims: Option<serde_json::Value>,
+}
+
+impl GatewayContext {
+    fn template_vars<'a>(&'a self, route: &'a str) -> TemplateVars<'a> {
+        TemplateVars {
+            client_ip: self.client.source.map(|addr| addr.ip()),
+            app_id: self.app_id.as_deref(),
+            route,
+            upstream: self.upstream.as_deref(),
+            captures: &self.path_captures,
+        }
+    }
+}
+
 impl GatewayProxy {
     pub fn new() -> Self {
         Self
@@ -42,115 +109,830 @@ impl GatewayProxy {
             },
         }
     }
+
+    /// The application bound to the client certificate of the connection
+    async fn get_cert_appid(&self, session: &Session) -> Option<String> {
+        let identity = client_identity(session)?;
+        crate::store::applications()
+            .read()
+            .await
+            .iter()
+            .find(|(_, app)| app.credentials().matches_client_cert(&identity))
+            .map(|(app_id, _)| app_id.to_string())
+    }
+
+    async fn find_route(&self, path: &str) -> Option<Arc<GatewayLoadBalancer>> {
+        crate::store::routes()
+            .read()
+            .await
+            .values()
+            .find(|lb| lb.matches_path(path))
+            .cloned()
+    }
+
+    async fn get_client_header(&self, session: &Session) -> ProxyHeader {
+        let local = session.server_addr().and_then(|a| a.as_inet()).copied();
+        match session.client_addr().and_then(|a| a.as_inet()) {
+            Some(peer) => real_client_header(*peer, local).await,
+            None => ProxyHeader {
+                source: None,
+                destination: local,
+            },
+        }
+    }
 }
 
 #[async_trait]
 impl ProxyHttp for GatewayProxy {
-    type CTX = ();
-    fn new_ctx(&self) -> () {
-        ()
+    type CTX = GatewayContext;
+    fn new_ctx(&self) -> Self::CTX {
+        GatewayContext::default()
+    }
+
+    async fn early_request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.client = self.get_client_header(session).await;
+        Ok(())
     }
 
-    async fn upstream_peer(&self, session: &mut Session, _ctx: &mut ()) -> Result<Box<HttpPeer>> {
-        let path = match session.req_header().uri.path() {
-            path => path,
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        let lb = match ctx.route.clone() {
+            Some(lb) => lb,
+            None => return Error::e_explain(ErrorType::ConnectNoRoute, "no route"),
         };
 
-        let upstream = {
-            let reoutes = &crate::store::routes().read().await;
+        if let Some(new_path) = lb.rewrite_path(session.req_header().uri.path()) {
+            let req = session.req_header_mut();
+            let mut uri = req.uri.clone().into_parts();
+            uri.path_and_query = uri.path_and_query.map(|pq| {
+                let query = pq.query();
+                let path_and_query = match query {
+                    Some(query) => format!("{}?{}", new_path, query),
+                    None => new_path.to_string(),
+                };
+                PathAndQuery::from_str(&path_and_query).unwrap()
+            });
+            req.set_uri(Uri::from_parts(uri).unwrap());
+        }
 
-            let lb = match reoutes.iter().find_map(|(_, lb)| {
-                if lb.matches_path(path) {
-                    Some(lb)
-                } else {
-                    None
-                }
-            }) {
-                Some(lb) => lb,
-                None => return Err(Error::new(ErrorType::ConnectNoRoute)),
-            };
+        let ext = get_ext_value(session);
 
-            // if let Some(rate_limiter) = lb.rate_limit() {
-            //     if let Err(e) = rate_limiter.check_rate_limit(session).await {
-            //         error!("Rate limit error: {:?}", e);
-            //         // Handle rate limit exceeded
-            //         return Err(Error::new(ErrorType::Custom("Rate limit exceeded:")));
-            //     }
-            // }
-
-            if let Some(new_path) = lb.rewrite_path(path) {
-                let req = session.req_header_mut();
-                let mut uri = req.uri.clone().into_parts();
-                uri.path_and_query = uri.path_and_query.map(|pq| {
-                    let query = pq.query();
-                    let path_and_query = match query {
-                        Some(query) => format!("{}?{}", new_path, query),
-                        None => new_path.to_string(),
-                    };
-                    PathAndQuery::from_str(&path_and_query).unwrap()
-                });
-                req.set_uri(Uri::from_parts(uri).unwrap());
-            }
-
-            let ext = get_ext_value(session);
-
-            match lb.lb().select_with(b"", 256, |backend, health| {
-                if backend.ext.is_empty() {
-                    return health;
-                }
+        let upstream = match lb.lb().select_with(b"", 256, |backend, health| {
+            if backend.ext.is_empty() {
+                return health;
+            }
 
-                // Check if the backend has a label for the extension
-                // and if it matches the extension from the request
-                // If the backend has a label for the extension, check if it matches
-                // the extension from the request
-                if let Some(ext) = ext.as_ref() {
-                    if let Some(lbext) = backend.ext.get::<String>() {
-                        if lbext == ext {
-                            return health;
-                        }
+            // Check if the backend has a label for the extension
+            // and if it matches the extension from the request
+            // If the backend has a label for the extension, check if it matches
+            // the extension from the request
+            if let Some(ext) = ext.as_ref() {
+                if let Some(lbext) = backend.ext.get::<String>() {
+                    if lbext == ext {
+                        return health;
                     }
                 }
+            }
 
-                return false;
-            }) {
-                Some(upstream) => {
-                    info!("upstream peer is: {} --> {:?}", lb.name(), upstream,);
-                    upstream
-                }
-                None => return Err(Error::new(ErrorType::ConnectNoRoute)),
+            return false;
+        }) {
+            Some(upstream) => {
+                info!(
+                    "upstream peer is: {:?} --> {} --> {:?}",
+                    ctx.client.source,
+                    lb.name(),
+                    upstream,
+                );
+                ctx.upstream = Some(upstream.addr.to_string());
+                upstream
             }
+            None => return Error::e_explain(ErrorType::ConnectNoRoute, "no healthy upstream"),
+        };
+
+        let (tls, sni) = match lb.upstream_rewrite().filter(|rewrite| rewrite.tls()) {
+            Some(rewrite) => (true, rewrite.sni(session.req_header())),
+            None => (false, "app".to_string()),
         };
+        let mut peer = Box::new(match &upstream.addr {
+            SocketAddr::Inet(addr) => HttpPeer::new(addr, tls, sni),
+            SocketAddr::Unix(addr) => {
+                let path = addr
+                    .as_pathname()
+                    .and_then(|p| p.to_str())
+                    .unwrap_or_default();
+                HttpPeer::new_uds(path, tls, sni)?
+            }
+        });
+        let route = ctx.route.as_ref();
+        if let Some(version) = route.and_then(|lb| lb.upstream_proxy_protocol()) {
+            peer.options.custom_l4 =
+                Some(Arc::new(ProxyProtocolConnector::new(version, ctx.client)));
+            // the header is sent once per connection, never share it between clients
+            let mut hasher = DefaultHasher::new();
+            ctx.client.source.hash(&mut hasher);
+            peer.group_key = hasher.finish();
+        }
+
+        if route.and_then(|lb| lb.grpc()).is_some() {
+            // grpc backends speak h2c with prior knowledge
+            peer.options.set_http_version(2, 2);
+            if let Some(timeout) = grpc_timeout(session) {
+                peer.options.read_timeout = Some(timeout);
+            }
+        }
 
-        let peer = Box::new(HttpPeer::new(upstream, false, "app".to_string()));
+        if let Some(streaming) = route.and_then(|lb| lb.streaming()) {
+            let websocket = is_websocket_upgrade(session);
+            let timeout = if websocket || accepts_event_stream(session) {
+                streaming.stream_idle_timeout
+            } else {
+                streaming.idle_timeout
+            };
+            if let Some(timeout) = timeout {
+                peer.options.read_timeout = Some(timeout);
+                peer.options.write_timeout = Some(timeout);
+                if websocket {
+                    session.set_read_timeout(timeout);
+                }
+            }
+        }
         Ok(peer)
     }
 
-    async fn request_filter(&self, session: &mut Session, _ctx: &mut Self::CTX) -> Result<bool>
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
     where
         Self::CTX: Send + Sync,
     {
-        let clinet_id = self.get_request_appid(session);
-        if let Some(clinet_id) = clinet_id {
-            if let Some(application) = crate::store::applications().read().await.get(&clinet_id) {
-                let rl = application.rate_limiter();
-                let curr_window_requests = rl.increase(&clinet_id);
-                if curr_window_requests > rl.max_req_per_second() {
-                    error!("Rate limit exceeded for client: {}", clinet_id);
-                    let mut header = ResponseHeader::build(429, None).unwrap();
-                    header
-                        .insert_header("X-Rate-Limit-Limit", rl.max_req_per_second().to_string())
-                        .unwrap();
-                    session.set_keepalive(None);
+        // the credentials of an authenticated application stay at the gateway
+        if ctx.app_id.is_some() {
+            for name in [
+                GATEWAY_APIKEY,
+                GATEWAY_SIGNATURE,
+                GATEWAY_TIMESTAMP,
+                GATEWAY_NONCE,
+            ] {
+                upstream_request.remove_header(name);
+            }
+        }
+        if let Some(rewrite) = ctx.route.as_ref().and_then(|lb| lb.upstream_rewrite()) {
+            rewrite.apply(upstream_request, ctx.upstream.as_deref())?;
+        }
+        if let Some(lb) = &ctx.route
+            && let Some(rules) = lb.request_headers()
+        {
+            rules.apply(upstream_request, &ctx.template_vars(lb.name()));
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if ctx.route.as_ref().is_some_and(|lb| lb.cache().is_some()) {
+            upstream_response
+                .insert_header(GATEWAY_CACHE_STATUS, session.cache.phase().as_str())?;
+        }
+
+        apply_response_rules(ctx, session.req_header(), upstream_response)?;
+
+        if let Some(lb) = ctx.route.as_ref().filter(|lb| lb.streaming().is_some()) {
+            ctx.streaming = streaming_guard(lb, upstream_response)?;
+        }
+
+        // streamed responses must not wait for an encoder to fill a block
+        if ctx.streaming.is_none()
+            && let Some(compression) = ctx.route.as_ref().and_then(|lb| lb.compression())
+        {
+            ctx.encoder = compression.encoder(session.req_header(), upstream_response);
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(encoder) = &mut ctx.encoder {
+            let data = body.as_deref().unwrap_or_default();
+            *body = Some(encoder.encode(data, end_of_stream)?);
+        }
+        Ok(None)
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.route = self.find_route(session.req_header().uri.path()).await;
+        if let Some(lb) = ctx.route.as_ref().filter(|lb| lb.uses_templates()) {
+            ctx.path_captures = lb.path_captures(session.req_header().uri.path());
+        }
+
+        // browsers send preflights without credentials, so they are
+        // answered before any check
+        if let Some(cors) = ctx.route.as_ref().and_then(|lb| lb.cors())
+            && is_preflight(session.req_header())
+        {
+            match cors.preflight(session.req_header()) {
+                Ok(answer) => {
                     session
-                        .write_response_header(Box::new(header), true)
-                        .await?;
+                        .write_response_header(Box::new(answer), true)
+                        .await?
+                }
+                Err(message) => respond_error(session, 403, message).await?,
+            }
+            return Ok(true);
+        }
+
+        let client_ip = ctx.client.source.map(|addr| addr.ip());
+        // a certificate bound to an application identifies it without a header
+        let clinet_id = match self.get_cert_appid(session).await {
+            Some(app_id) => Some(app_id),
+            None => self.get_request_appid(session),
+        };
+        let application = match &clinet_id {
+            Some(clinet_id) => crate::store::applications()
+                .read()
+                .await
+                .get(clinet_id)
+                .cloned(),
+            None => None,
+        };
+        // address restrictions apply before anything else is looked at
+        let route_name = ctx.route.as_ref().map(|lb| lb.name().to_string());
+        let app_id = application.as_ref().and(clinet_id.clone());
+        let scopes = [
+            Some(IpAccessScope::Global),
+            route_name.clone().map(IpAccessScope::Route),
+            app_id.clone().map(IpAccessScope::Application),
+        ];
+        if let Some(scope) = scopes
+            .into_iter()
+            .flatten()
+            .find(|scope| !crate::store::ip_access().allows(scope, client_ip))
+        {
+            let decision = AccessDecision::IpDenied;
+            let route_name = route_name.as_deref().unwrap_or("-");
+            let app_id = app_id.as_deref().unwrap_or("-");
+            metrics::ACCESS_DECISIONS
+                .with_label_values(&[route_name, app_id, decision.as_str()])
+                .inc();
+            error!(
+                "Client {:?} denied by the ip access of {:?}",
+                client_ip, scope
+            );
+            respond_error(session, 403, decision.message()).await?;
+            return Ok(true);
+        }
+
+        // the claimed application id is only trusted with valid credentials
+        if let (Some(clinet_id), Some(application)) = (&clinet_id, &application)
+            && application.credentials().required()
+            && let Err(e) = application.credentials().verify(session).await?
+        {
+            error!("Invalid credentials for application {}: {}", clinet_id, e);
+            respond_error(session, 401, &e.to_string()).await?;
+            return Ok(true);
+        }
+        ctx.app_id = app_id;
+
+        // end-user tokens are checked before anything is counted for them
+        if let Some(lb) = ctx.route.clone()
+            && let Some(jwt) = lb.jwt()
+        {
+            match jwt.verify(bearer_token(session)).await {
+                Ok(claims) => {
+                    for (name, value) in jwt.claim_headers(&claims) {
+                        let req = session.req_header_mut();
+                        match value {
+                            Some(value) => {
+                                if let Err(e) = req.insert_header(name.to_string(), value) {
+                                    error!("Failed to forward claim in {}: {:?}", name, e);
+                                }
+                            }
+                            None => {
+                                req.remove_header(name);
+                            }
+                        }
+                    }
+                    ctx.claims = Some(claims);
+                }
+                Err(e) => {
+                    error!("Invalid jwt for route {}: {}", lb.name(), e);
+                    respond_error(session, 401, &e.to_string()).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if let Some(lb) = ctx.route.clone()
+            && let Some(forward_auth) = lb.forward_auth()
+        {
+            let answer = match forward_auth.check(session.req_header(), client_ip).await {
+                Ok(answer) => answer,
+                Err(e) => {
+                    error!("Forward auth failed for route {}: {:?}", lb.name(), e);
+                    respond_error(session, 500, "auth service unavailable").await?;
                     return Ok(true);
                 }
+            };
+            if !answer.allowed() {
+                info!(
+                    "Forward auth denied route {} with {}",
+                    lb.name(),
+                    answer.status
+                );
+                respond_auth_answer(session, &answer).await?;
+                return Ok(true);
+            }
+            // clients cannot supply the headers the auth service vouches for
+            let req = session.req_header_mut();
+            for name in forward_auth.response_headers() {
+                req.remove_header(name);
+            }
+            for (name, value) in &answer.headers {
+                req.append_header(name.clone(), value.clone())?;
             }
         }
 
+        if let Some(lb) = ctx.route.clone() {
+            let method = session.req_header().method.as_str();
+            let decision = match &application {
+                Some(application) => match application.acl() {
+                    Some(acl) => Some(acl.check(lb.name(), method)),
+                    None => lb.require_application().then_some(AccessDecision::Allowed),
+                },
+                None => lb
+                    .require_application()
+                    .then_some(AccessDecision::UnknownApplication),
+            };
+            if let Some(decision) = decision {
+                // unknown application ids are not labeled, clients choose them freely
+                let app_id = application
+                    .as_ref()
+                    .and(clinet_id.as_deref())
+                    .unwrap_or("-");
+                metrics::ACCESS_DECISIONS
+                    .with_label_values(&[lb.name(), app_id, decision.as_str()])
+                    .inc();
+                if decision != AccessDecision::Allowed {
+                    error!(
+                        "Access denied for application {} to route {}: {}",
+                        app_id,
+                        lb.name(),
+                        decision.as_str()
+                    );
+                    respond_error(session, 403, decision.message()).await?;
+                    return Ok(true);
+                }
+                debug!(
+                    "Access allowed for application {} to route {}",
+                    app_id,
+                    lb.name()
+                );
+            }
+        }
+
+        let status = match (&clinet_id, &application) {
+            (Some(clinet_id), Some(application)) => {
+                let rl = application.rate_limiter();
+                Some(count_rate_limit("application", clinet_id, rl, clinet_id).await)
+            }
+            // unidentified callers share the default policy
+            _ => match crate::store::default_rate_limit() {
+                Some(policy) => {
+                    let key = policy.key.extract(
+                        session,
+                        client_ip,
+                        clinet_id.as_deref(),
+                        ctx.claims.as_ref(),
+                    );
+                    Some(count_rate_limit("default", "default", &policy.limiter, &key).await)
+                }
+                None => None,
+            },
+        };
+        if let Some(status) = status {
+            if status.exceeded() {
+                error!("Rate limit exceeded for {}: {}", status.scope, status.name);
+                respond_too_many_requests(session, &status).await?;
+                return Ok(true);
+            }
+            ctx.rate_limit = Some(status);
+        }
+
+        if let (Some(clinet_id), Some(application)) = (&clinet_id, &application)
+            && let Some(concurrency) = application.concurrency()
+        {
+            ctx.concurrency = concurrency.acquire().await;
+            if ctx.concurrency.is_none() {
+                error!(
+                    "Too many concurrent requests for application: {}",
+                    clinet_id
+                );
+                let status = concurrency_status(clinet_id, concurrency.limit());
+                respond_too_many_requests(session, &status).await?;
+                return Ok(true);
+            }
+        }
+
+        // route limits are enforced before any upstream is selected
+        let route = ctx.route.clone();
+        let route_limit = route.as_ref().and_then(|lb| {
+            let policy = lb.rate_limit()?;
+            let key = match policy.key.extract(
+                session,
+                client_ip,
+                clinet_id.as_deref(),
+                ctx.claims.as_ref(),
+            ) {
+                key if key.is_empty() => lb.name().to_string(),
+                key => format!("{}|{}", lb.name(), key),
+            };
+            Some((lb.name(), &policy.limiter, key))
+        });
+        let quota = match (&clinet_id, &application) {
+            (Some(clinet_id), Some(application)) => {
+                application.quota().map(|quota| (clinet_id.as_str(), quota))
+            }
+            _ => None,
+        };
+        let route_limit = route_limit
+            .as_ref()
+            .map(|(name, limiter, key)| (*name, *limiter, key.as_str()));
+        // quotas only count requests within every rate limit
+        match count_route_limit_and_quota(route_limit, quota, &crate::store::quotas()).await {
+            Ok(statuses) => statuses
+                .into_iter()
+                .for_each(|status| report_rate_limit(ctx, status)),
+            Err(status) => {
+                respond_too_many_requests(session, &status).await?;
+                return Ok(true);
+            }
+        }
+
+        // routes without upstreams answer once the request passed every check
+        if let Some(lb) = ctx.route.clone()
+            && let Some(action) = lb.action()
+        {
+            let path = session.req_header().uri.path();
+            let path = lb.rewrite_path(path).unwrap_or_else(|| path.to_string());
+            let rules = |req: &RequestHeader, resp: &mut ResponseHeader| {
+                apply_response_rules(ctx, req, resp)
+            };
+            let mut response = ActionResponse::new(session, &rules, lb.compression());
+            action
+                .answer(&mut response, &path, &ctx.template_vars(lb.name()))
+                .await?;
+            return Ok(true);
+        }
+
         Ok(false)
     }
+
+    fn request_cache_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
+        let method = &session.req_header().method;
+        // streams never end, so they are never complete in the cache
+        if let Some(cache) = ctx.route.as_ref().and_then(|lb| lb.cache())
+            && (method == http::Method::GET || method == http::Method::HEAD)
+            && !is_websocket_upgrade(session)
+            && !accepts_event_stream(session)
+        {
+            cache.enable(&mut session.cache);
+        }
+        Ok(())
+    }
+
+    fn cache_key_callback(&self, session: &Session, ctx: &mut Self::CTX) -> Result<CacheKey> {
+        match ctx
+            .route
+            .as_ref()
+            .and_then(|lb| Some((lb.name(), lb.cache()?)))
+        {
+            Some((name, cache)) => Ok(cache.key(name, session.req_header())),
+            None => Error::e_explain(ErrorType::InternalError, "route without cache"),
+        }
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable> {
+        Ok(match ctx.route.as_ref().and_then(|lb| lb.cache()) {
+            Some(cache) => cache.cacheable(session.req_header(), resp, ctx.app_id.as_deref()),
+            None => RespCacheable::Uncacheable(NoCacheReason::NeverEnabled),
+        })
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        let cache = ctx.route.as_ref()?.cache()?;
+        cache.variance(meta, req, ctx.app_id.as_deref())
+    }
+
+    fn should_serve_stale(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut Self::CTX,
+        error: Option<&Error>,
+    ) -> bool {
+        // how long a response may be served stale is up to its cache meta
+        error.is_none_or(|e| e.esource() == &ErrorSource::Upstream)
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        _ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // the connection is already dead
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        if code > 0 && session.response_written().is_none() {
+            let result = if is_grpc_request(session) {
+                let message = e
+                    .context
+                    .as_ref()
+                    .map_or(e.etype().as_str(), |c| c.as_str());
+                respond_grpc_error(session, GrpcStatus::from_error(e), message).await
+            } else {
+                session.respond_error(code).await
+            };
+            if let Err(e) = result {
+                error!("failed to send error response to downstream: {e}");
+            }
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+}
+
+async fn count_rate_limit(
+    scope: &'static str,
+    name: &str,
+    rl: &RateLimiter,
+    key: &str,
+) -> RateLimitStatus {
+    RateLimitStatus {
+        scope,
+        name: name.to_string(),
+        limit: rl.max_req_per_second(),
+        usage: rl.increase(key).await,
+    }
+}
+
+/// Count the route limit and then the quota of the application, a request
+/// rejected by the route limit is not counted against the quota
+async fn count_route_limit_and_quota(
+    route_limit: Option<(&str, &RateLimiter, &str)>,
+    quota: Option<(&str, &Quota)>,
+    quotas: &QuotaStore,
+) -> std::result::Result<Vec<RateLimitStatus>, RateLimitStatus> {
+    let mut statuses = vec![];
+    if let Some((name, limiter, key)) = route_limit {
+        let status = count_rate_limit("route", name, limiter, key).await;
+        if status.exceeded() {
+            error!("Rate limit exceeded for route: {}", key);
+            return Err(status);
+        }
+        statuses.push(status);
+    }
+    if let Some((app_id, quota)) = quota {
+        match quotas.consume(app_id, quota) {
+            Ok(usage) => statuses.push(quota_status(app_id, usage)),
+            Err(usage) => {
+                error!("Quota exceeded for application: {}", app_id);
+                return Err(quota_status(app_id, usage));
+            }
+        }
+    }
+    Ok(statuses)
+}
+
+fn quota_status(app_id: &str, usage: QuotaUsage) -> RateLimitStatus {
+    let reset = usage.reset_after();
+    RateLimitStatus {
+        scope: "quota",
+        name: app_id.to_string(),
+        limit: usage.limit as isize,
+        usage: RateLimitUsage {
+            count: usage.used as isize,
+            reset,
+            retry_after: reset,
+        },
+    }
+}
+
+/// A rejection by the concurrency limit, a slot may free up any time
+fn concurrency_status(app_id: &str, limit: u32) -> RateLimitStatus {
+    RateLimitStatus {
+        scope: "concurrency",
+        name: app_id.to_string(),
+        limit: limit as isize,
+        usage: RateLimitUsage {
+            count: limit as isize + 1,
+            reset: Duration::ZERO,
+            retry_after: Duration::from_secs(1),
+        },
+    }
+}
+
+/// The rate limit, cors and response header rules of the route, applied to
+/// proxied responses and the answers of route actions alike
+fn apply_response_rules(
+    ctx: &GatewayContext,
+    req: &RequestHeader,
+    resp: &mut ResponseHeader,
+) -> Result<()> {
+    if let Some(status) = &ctx.rate_limit {
+        for (name, value) in status.headers(rate_limit_header_style()) {
+            resp.insert_header(name, value)?;
+        }
+    }
+    if let Some(cors) = ctx.route.as_ref().and_then(|lb| lb.cors()) {
+        cors.apply(req, resp)?;
+    }
+    if let Some(lb) = &ctx.route
+        && let Some(rules) = lb.response_headers()
+    {
+        rules.apply(resp, &ctx.template_vars(lb.name()));
+    }
+    Ok(())
+}
+
+/// Report the limit closest to running out in the response
+fn report_rate_limit(ctx: &mut GatewayContext, status: RateLimitStatus) {
+    if ctx
+        .rate_limit
+        .as_ref()
+        .is_none_or(|s| status.remaining() < s.remaining())
+    {
+        ctx.rate_limit = Some(status);
+    }
+}
+
+fn rate_limit_header_style() -> RateLimitHeaderStyle {
+    crate::store::config()
+        .rate_limit_headers
+        .as_deref()
+        .and_then(RateLimitHeaderStyle::parse)
+        .unwrap_or(RateLimitHeaderStyle::Ietf)
+}
+
+async fn respond_too_many_requests(session: &mut Session, status: &RateLimitStatus) -> Result<()> {
+    if is_grpc_request(session) {
+        let message = format!("{} rate limit exceeded", status.scope);
+        return respond_grpc_error(session, GrpcStatus::ResourceExhausted, &message).await;
+    }
+    let body = Bytes::from(status.body());
+    let mut header = ResponseHeader::build(429, Some(7))?;
+    for (name, value) in status.headers(rate_limit_header_style()) {
+        header.insert_header(name, value)?;
+    }
+    header.insert_header("Retry-After", status.retry_after().to_string())?;
+    header.insert_header(http::header::CONTENT_TYPE, "application/json")?;
+    header.insert_header(http::header::CONTENT_LENGTH, body.len().to_string())?;
+    session.set_keepalive(None);
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session.write_response_body(Some(body), true).await
+}
+
+/// A JSON error response, or the matching gRPC status for gRPC requests
+pub async fn respond_error(session: &mut Session, code: u16, message: &str) -> Result<()> {
+    if is_grpc_request(session) {
+        return respond_grpc_error(session, GrpcStatus::from_http_status(code), message).await;
+    }
+    let (header, body) = error_response(code, message)?;
+    session.set_keepalive(None);
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session.write_response_body(Some(body), true).await
+}
+
+/// The JSON error response of the gateway
+pub fn error_response(code: u16, message: &str) -> Result<(ResponseHeader, Bytes)> {
+    let error = http::StatusCode::from_u16(code)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("error")
+        .to_lowercase()
+        .replace(' ', "_");
+    let body = Bytes::from(serde_json::json!({ "error": error, "message": message }).to_string());
+    let mut header = ResponseHeader::build(code, Some(4))?;
+    header.insert_header(http::header::CONTENT_TYPE, "application/json")?;
+    header.insert_header(http::header::CONTENT_LENGTH, body.len().to_string())?;
+    Ok((header, body))
+}
+
+/// Return the denial of the auth service to the client as it is
+async fn respond_auth_answer(session: &mut Session, answer: &AuthAnswer) -> Result<()> {
+    if is_grpc_request(session) {
+        let status = GrpcStatus::from_http_status(answer.status);
+        return respond_grpc_error(session, status, "denied by auth service").await;
+    }
+    let mut header = ResponseHeader::build(answer.status, Some(answer.headers.len() + 1))?;
+    for (name, value) in &answer.headers {
+        header.append_header(name.clone(), value.clone())?;
+    }
+    header.insert_header(http::header::CONTENT_LENGTH, answer.body.len().to_string())?;
+    session.set_keepalive(None);
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session
+        .write_response_body(Some(answer.body.clone()), true)
+        .await
+}
+
+/// The guard of an upgraded websocket or event stream response, which body
+/// filters pass through unbuffered
+fn streaming_guard(
+    lb: &GatewayLoadBalancer,
+    upstream_response: &mut ResponseHeader,
+) -> Result<Option<StreamingGuard>> {
+    let event_stream = upstream_response
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(EVENT_STREAM));
+    if upstream_response.status == http::StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(Some(StreamingGuard::new(lb.name(), "websocket")));
+    } else if event_stream {
+        // keep any buffering proxy in front of us from holding events back
+        upstream_response.insert_header("X-Accel-Buffering", "no")?;
+        if !upstream_response
+            .headers
+            .contains_key(http::header::CACHE_CONTROL)
+        {
+            upstream_response.insert_header(http::header::CACHE_CONTROL, "no-cache")?;
+        }
+        return Ok(Some(StreamingGuard::new(lb.name(), "sse")));
+    }
+    Ok(None)
+}
+
+fn is_websocket_upgrade(session: &Session) -> bool {
+    session.is_upgrade_req()
+        && session
+            .get_header(http::header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+fn accepts_event_stream(session: &Session) -> bool {
+    session
+        .get_header(http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(EVENT_STREAM))
 }
 
 fn get_ext_value(session: &Session) -> Option<String> {
@@ -176,3 +958,104 @@ fn get_query_param(query_str: &str, param_name: &str) -> Option<String> {
         .find(|(key, _)| key == param_name)
         .map(|(_, value)| value.into_owned())
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        action::RouteAction,
+        config::{GatewayCorsConfig, GatewayRespondConfig},
+        cors::CorsPolicy,
+        lb::{static_discovery, GatewayMatchRule},
+        quota::QuotaPeriod,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_limit_before_quota() {
+        let path =
+            std::env::temp_dir().join(format!("gateway_quota_rl_{}.json", std::process::id()));
+        let quotas = QuotaStore::load(path.to_str().unwrap());
+        let quota = Quota {
+            limit: 10,
+            period: QuotaPeriod::Day,
+        };
+        let limiter = RateLimiter::new(Duration::from_secs(60), 1);
+        let count = || {
+            count_route_limit_and_quota(
+                Some(("api", &limiter, "api|10.0.0.1")),
+                Some(("app", &quota)),
+                &quotas,
+            )
+        };
+
+        let statuses = count().await.unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(quotas.usage("app", &quota).used, 1);
+
+        // a request rejected by the route limit leaves the quota unchanged
+        let status = count().await.unwrap_err();
+        assert_eq!(status.scope, "route");
+        assert_eq!(quotas.usage("app", &quota).used, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cors_on_action_route() {
+        let action = RouteAction::respond(&GatewayRespondConfig {
+            status: None,
+            headers: None,
+            body: Some("pong".to_string()),
+            file: None,
+        })
+        .unwrap();
+        let cors = CorsPolicy::new(&GatewayCorsConfig {
+            allow_origins: Some(vec!["https://app.example.com".to_string()]),
+            allow_origin_regex: None,
+            allow_methods: None,
+            allow_headers: None,
+            allow_credentials: None,
+            max_age: None,
+            expose_headers: None,
+        })
+        .unwrap();
+        let options = GatewayLoadBalancerOptions::new(
+            GatewayMatchRule::Any,
+            static_discovery(&[]).unwrap(),
+            false,
+        )
+        .with_action(action)
+        .with_cors(cors);
+        let lb = Arc::new(GatewayLoadBalancer::new("ping", options));
+        let ctx = GatewayContext {
+            route: Some(lb.clone()),
+            ..Default::default()
+        };
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /ping HTTP/1.1\r\nHost: gw\r\nOrigin: https://app.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut session = Session::new_h1(Box::new(server));
+        assert!(session.read_request().await.unwrap());
+
+        let rules =
+            |req: &RequestHeader, resp: &mut ResponseHeader| apply_response_rules(&ctx, req, resp);
+        let mut response = ActionResponse::new(&mut session, &rules, None);
+        let action = lb.action().unwrap();
+        action
+            .answer(&mut response, "/ping", &ctx.template_vars(lb.name()))
+            .await
+            .unwrap();
+        drop(session);
+
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).await.unwrap();
+        let answer = answer.to_ascii_lowercase();
+        assert!(answer.starts_with("http/1.1 200"));
+        assert!(answer.contains("access-control-allow-origin: https://app.example.com\r\n"));
+        assert!(answer.ends_with("pong"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use pingora::{
+    connectors::L4Connect,
+    prelude::*,
+    protocols::l4::{socket::SocketAddr as PingoraSocketAddr, stream::Stream},
+    server::ShutdownWatch,
+    services::background::BackgroundService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+use tracing::{error, info};
+
+use crate::stream::connect_stream;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    pub fn parse(version: &str) -> Option<Self> {
+        match version {
+            "v1" => Some(ProxyProtocolVersion::V1),
+            "v2" => Some(ProxyProtocolVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// The addresses carried by a PROXY protocol header
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+/// Reads the PROXY protocol header from connections of trusted sources,
+/// other connections are taken as they are.
+#[derive(PartialEq)]
+pub struct ProxyProtocolAcceptor {
+    trusted_cidrs: Vec<IpNet>,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(trusted_cidrs: Vec<IpNet>) -> Self {
+        Self { trusted_cidrs }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_cidrs.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    /// Returns the real client and destination address of the connection
+    pub async fn accept<R: AsyncRead + Unpin>(
+        &self,
+        stream: &mut R,
+        peer: SocketAddr,
+        local: SocketAddr,
+    ) -> anyhow::Result<ProxyHeader> {
+        let mut header = if self.is_trusted(peer.ip()) {
+            read_proxy_header(stream).await?
+        } else {
+            ProxyHeader::default()
+        };
+        header.source.get_or_insert(peer);
+        header.destination.get_or_insert(local);
+        Ok(header)
+    }
+}
+
+/// Terminates the PROXY protocol in front of the http proxy: every accepted
+/// connection is forwarded to the internal http listener and the real client
+/// address is recorded for the loopback connection carrying it.
+pub struct ProxyProtocolListener {
+    addr: String,
+    forward_to: String,
+    acceptor: Arc<ProxyProtocolAcceptor>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(addr: &str, forward_to: &str, acceptor: ProxyProtocolAcceptor) -> Self {
+        Self {
+            addr: addr.to_string(),
+            forward_to: forward_to.to_string(),
+            acceptor: Arc::new(acceptor),
+        }
+    }
+}
+
+async fn forward(
+    mut downstream: TcpStream,
+    peer: SocketAddr,
+    forward_to: SocketAddr,
+    acceptor: Arc<ProxyProtocolAcceptor>,
+) -> anyhow::Result<()> {
+    let local = downstream.local_addr()?;
+    let header = acceptor.accept(&mut downstream, peer, local).await?;
+
+    let socket = match forward_to {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(forward_to.ip(), 0))?;
+    let loopback = socket.local_addr()?;
+
+    crate::store::proxy_protocol_headers()
+        .write()
+        .await
+        .insert(loopback, header);
+    let result = async {
+        let mut upstream = socket.connect(forward_to).await?;
+        tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    crate::store::proxy_protocol_headers()
+        .write()
+        .await
+        .remove(&loopback);
+
+    result
+}
+
+#[async_trait]
+impl BackgroundService for ProxyProtocolListener {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        let (listener, forward_to) = match (
+            TcpListener::bind(&self.addr).await,
+            self.forward_to.parse::<SocketAddr>(),
+        ) {
+            (Ok(listener), Ok(forward_to)) => (listener, forward_to),
+            (Err(e), _) => {
+                error!(
+                    "Failed to bind proxy protocol listener {}: {:?}",
+                    self.addr, e
+                );
+                return;
+            }
+            (_, Err(e)) => {
+                error!("Invalid forward address {}: {:?}", self.forward_to, e);
+                return;
+            }
+        };
+        info!(
+            "proxy protocol listener listening on {} --> {}",
+            self.addr, self.forward_to
+        );
+
+        let mut shutdown = shutdown.clone();
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                }
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, peer)) => {
+                            let acceptor = self.acceptor.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = forward(stream, peer, forward_to, acceptor).await {
+                                    error!("proxy protocol connection from {} failed: {:?}", peer, e);
+                                }
+                            });
+                        }
+                        Err(e) => error!("accept on {} failed: {:?}", self.addr, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Resolve the real client address of a connection accepted by the http
+/// proxy, falling back to the tcp peer address.
+pub async fn real_client_header(peer: SocketAddr, local: Option<SocketAddr>) -> ProxyHeader {
+    if let Some(header) = crate::store::proxy_protocol_headers()
+        .read()
+        .await
+        .get(&peer)
+    {
+        return *header;
+    }
+    ProxyHeader {
+        source: Some(peer),
+        destination: local,
+    }
+}
+
+/// Connects to upstreams that expect a PROXY protocol header
+#[derive(Debug)]
+pub struct ProxyProtocolConnector {
+    version: ProxyProtocolVersion,
+    header: ProxyHeader,
+}
+
+impl ProxyProtocolConnector {
+    pub fn new(version: ProxyProtocolVersion, header: ProxyHeader) -> Self {
+        Self { version, header }
+    }
+
+    pub async fn connect_stream(&self, addr: &PingoraSocketAddr) -> std::io::Result<Stream> {
+        let mut stream = connect_stream(addr).await?;
+        stream
+            .write_all(&encode_proxy_header(self.version, &self.header))
+            .await?;
+        stream.flush().await?;
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl L4Connect for ProxyProtocolConnector {
+    async fn connect(&self, addr: &PingoraSocketAddr) -> Result<Stream> {
+        self.connect_stream(addr)
+            .await
+            .or_err(ErrorType::ConnectError, "proxy protocol connect failed")
+    }
+}
+
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<ProxyHeader> {
+    // the shortest v1 header "PROXY UNKNOWN\r\n" is longer than the v2 signature
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut buf).await?;
+
+    if buf == V2_SIGNATURE {
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        let mut payload = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+        stream.read_exact(&mut payload).await?;
+        return parse_v2(head[0], head[1], &payload);
+    }
+
+    if !buf.starts_with(b"PROXY ") {
+        anyhow::bail!("missing proxy protocol header");
+    }
+    while !buf.ends_with(b"\r\n") {
+        if buf.len() >= V1_MAX_LENGTH {
+            anyhow::bail!("proxy protocol v1 header too long");
+        }
+        buf.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&buf[..buf.len() - 2])?)
+}
+
+fn parse_v1(line: &str) -> anyhow::Result<ProxyHeader> {
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => Ok(ProxyHeader {
+            source: Some(SocketAddr::new(src.parse()?, sport.parse()?)),
+            destination: Some(SocketAddr::new(dst.parse()?, dport.parse()?)),
+        }),
+        _ => anyhow::bail!("invalid proxy protocol v1 header: {}", line),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> anyhow::Result<ProxyHeader> {
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("invalid proxy protocol v2 version: {}", ver_cmd >> 4);
+    }
+    // LOCAL command, e.g. health checks of the balancer itself
+    if ver_cmd & 0x0f == 0 {
+        return Ok(ProxyHeader::default());
+    }
+
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let src: [u8; 4] = payload[0..4].try_into()?;
+            let dst: [u8; 4] = payload[4..8].try_into()?;
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src.into(), port(&payload[8..10]))),
+                destination: Some(SocketAddr::new(dst.into(), port(&payload[10..12]))),
+            })
+        }
+        0x2 if payload.len() >= 36 => {
+            let src: [u8; 16] = payload[0..16].try_into()?;
+            let dst: [u8; 16] = payload[16..32].try_into()?;
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(src.into(), port(&payload[32..34]))),
+                destination: Some(SocketAddr::new(dst.into(), port(&payload[34..36]))),
+            })
+        }
+        // unix or unspecified addresses carry no ip information
+        _ => Ok(ProxyHeader::default()),
+    }
+}
+
+pub fn encode_proxy_header(version: ProxyProtocolVersion, header: &ProxyHeader) -> Vec<u8> {
+    let addrs = match (header.source, header.destination) {
+        (Some(src), Some(dst)) if src.is_ipv4() == dst.is_ipv4() => Some((src, dst)),
+        _ => None,
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((src, dst)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src.is_ipv4() { "TCP4" } else { "TCP6" },
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            match addrs {
+                Some((src, dst)) => {
+                    let mut payload = vec![];
+                    match (src.ip(), dst.ip()) {
+                        (IpAddr::V4(s), IpAddr::V4(d)) => {
+                            buf.extend([0x21, 0x11]);
+                            payload.extend(s.octets());
+                            payload.extend(d.octets());
+                        }
+                        (IpAddr::V6(s), IpAddr::V6(d)) => {
+                            buf.extend([0x21, 0x21]);
+                            payload.extend(s.octets());
+                            payload.extend(d.octets());
+                        }
+                        _ => unreachable!("address families are checked above"),
+                    }
+                    payload.extend(src.port().to_be_bytes());
+                    payload.extend(dst.port().to_be_bytes());
+                    buf.extend((payload.len() as u16).to_be_bytes());
+                    buf.extend(payload);
+                }
+                None => buf.extend([0x20, 0x00, 0x00, 0x00]),
+            }
+            buf
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy_header_roundtrip() {
+        let header = ProxyHeader {
+            source: Some("203.0.113.7:51234".parse().unwrap()),
+            destination: Some("10.0.0.1:6188".parse().unwrap()),
+        };
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let mut encoded = encode_proxy_header(version, &header);
+            encoded.extend(b"GET / HTTP/1.1\r\n");
+            let mut reader = encoded.as_slice();
+
+            assert_eq!(read_proxy_header(&mut reader).await.unwrap(), header);
+            // the request itself is left untouched
+            assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acceptor_ignores_untrusted_peers() {
+        let acceptor = ProxyProtocolAcceptor::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let peer: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let local: SocketAddr = "10.0.0.1:6188".parse().unwrap();
+        let mut reader = b"PROXY TCP4 1.2.3.4 10.0.0.1 5 6188\r\n".as_slice();
+
+        let header = acceptor.accept(&mut reader, peer, local).await.unwrap();
+        assert_eq!(header.source, Some(peer));
+        assert!(reader.starts_with(b"PROXY"));
+    }
+}
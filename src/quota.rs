@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+const QUOTA_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// Calendar periods in UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    pub fn parse(period: &str) -> Option<Self> {
+        match period {
+            "day" => Some(QuotaPeriod::Day),
+            "month" => Some(QuotaPeriod::Month),
+            _ => None,
+        }
+    }
+
+    /// The period containing `now`, e.g., "2026-10-18" or "2026-10"
+    fn key(&self, now: u64) -> String {
+        let (y, m, d) = civil_from_days((now / SECONDS_PER_DAY) as i64);
+        match self {
+            QuotaPeriod::Day => format!("{:04}-{:02}-{:02}", y, m, d),
+            QuotaPeriod::Month => format!("{:04}-{:02}", y, m),
+        }
+    }
+
+    /// The unix time the period containing `now` ends
+    fn reset_at(&self, now: u64) -> u64 {
+        let days = (now / SECONDS_PER_DAY) as i64;
+        let next = match self {
+            QuotaPeriod::Day => days + 1,
+            QuotaPeriod::Month => match civil_from_days(days) {
+                (y, 12, _) => days_from_civil(y + 1, 1, 1),
+                (y, m, _) => days_from_civil(y, m + 1, 1),
+            },
+        };
+        next as u64 * SECONDS_PER_DAY
+    }
+}
+
+pub struct Quota {
+    pub limit: u64,
+    pub period: QuotaPeriod,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QuotaCounter {
+    period: String,
+    used: u64,
+}
+
+/// The usage of a quota in its current period
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub period: String,
+    /// Unix time the quota is reset
+    pub reset_at: u64,
+}
+
+impl QuotaUsage {
+    pub fn reset_after(&self) -> Duration {
+        let now = unix_now();
+        Duration::from_secs(self.reset_at.saturating_sub(now))
+    }
+}
+
+/// Quota counters of all applications, persisted as a JSON file so they
+/// survive restarts.
+pub struct QuotaStore {
+    path: PathBuf,
+    counters: Mutex<HashMap<String, QuotaCounter>>,
+    dirty: AtomicBool,
+}
+
+impl QuotaStore {
+    pub fn load(path: &str) -> Self {
+        let counters = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                error!("Invalid quota file {}: {:?}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                error!("Failed to read quota file {}: {:?}", path, e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path: PathBuf::from(path),
+            counters: Mutex::new(counters),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a request against the quota, the request is only counted and
+    /// `Ok` while the quota is not used up
+    pub fn consume(&self, app_id: &str, quota: &Quota) -> Result<QuotaUsage, QuotaUsage> {
+        let now = unix_now();
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(app_id.to_string()).or_default();
+        let period = quota.period.key(now);
+        if counter.period != period {
+            *counter = QuotaCounter { period, used: 0 };
+        }
+        if counter.used >= quota.limit {
+            return Err(usage(counter, quota, now));
+        }
+        counter.used += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(usage(counter, quota, now))
+    }
+
+    pub fn usage(&self, app_id: &str, quota: &Quota) -> QuotaUsage {
+        let now = unix_now();
+        let period = quota.period.key(now);
+        let counter = match self.counters.lock().unwrap().get(app_id) {
+            Some(counter) if counter.period == period => counter.clone(),
+            _ => QuotaCounter { period, used: 0 },
+        };
+        usage(&counter, quota, now)
+    }
+
+    pub fn reset(&self, app_id: &str) {
+        self.counters.lock().unwrap().remove(app_id);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the counters when they changed since the last flush
+    pub fn flush(&self) -> anyhow::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&*self.counters.lock().unwrap())?;
+        // replace the file atomically so a crash never leaves it truncated
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for QuotaStore {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        info!("quota store persisting to {}", self.path.display());
+        let mut shutdown = shutdown.clone();
+        let mut interval = tokio::time::interval(QUOTA_FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+            if let Err(e) = self.flush() {
+                error!("Failed to persist quotas: {:?}", e);
+            }
+        }
+        if let Err(e) = self.flush() {
+            error!("Failed to persist quotas: {:?}", e);
+        }
+    }
+}
+
+fn usage(counter: &QuotaCounter, quota: &Quota, now: u64) -> QuotaUsage {
+    QuotaUsage {
+        limit: quota.limit,
+        used: counter.used,
+        remaining: quota.limit.saturating_sub(counter.used),
+        period: counter.period.clone(),
+        reset_at: quota.period.reset_at(now),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The (year, month, day) of days since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quota_period() {
+        // 2026-12-31T12:00:00Z
+        let now = 1798718400;
+
+        assert_eq!(QuotaPeriod::Day.key(now), "2026-12-31");
+        assert_eq!(QuotaPeriod::Month.key(now), "2026-12");
+        // 2027-01-01T00:00:00Z
+        assert_eq!(QuotaPeriod::Day.reset_at(now), 1798761600);
+        assert_eq!(QuotaPeriod::Month.reset_at(now), 1798761600);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_quota_store_persists() {
+        let path = std::env::temp_dir().join(format!("gateway_quota_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let quota = Quota {
+            limit: 2,
+            period: QuotaPeriod::Month,
+        };
+
+        let store = QuotaStore::load(path);
+        assert!(store.consume("app", &quota).is_ok());
+        store.flush().unwrap();
+
+        let store = QuotaStore::load(path);
+        assert_eq!(store.consume("app", &quota).unwrap().remaining, 0);
+        assert!(store.consume("app", &quota).is_err());
+        store.reset("app");
+        assert_eq!(store.usage("app", &quota).used, 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
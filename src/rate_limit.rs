@@ -1,27 +1,702 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use pingora::proxy::Session;
 use pingora_limits::rate::Rate;
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Script,
+};
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+const REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long to limit locally after redis failed
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Drop the full buckets of idle keys once this many keys are tracked
+const TOKEN_BUCKET_PRUNE_SIZE: usize = 10_000;
+/// The placeholder of a key part missing from the request
+const KEY_PART_MISSING: &str = "-";
+
+/// Fixed window, the window starts with the first request of the key
+const FIXED_WINDOW_SCRIPT: &str = r#"
+local count = redis.call('INCR', KEYS[1])
+if count == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return {count, redis.call('PTTL', KEYS[1])}
+"#;
+
+/// GCRA keyed by the theoretical arrival time in microseconds, returns the
+/// tokens in use and how far the arrival time is ahead of now
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local interval = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local tat = math.max(tonumber(redis.call('GET', KEYS[1]) or now), now)
+local level = math.ceil((tat + interval - now) / interval)
+if level <= burst then
+    local ttl = math.ceil((tat + interval - now) / 1000) + 1
+    redis.call('SET', KEYS[1], string.format('%.0f', tat + interval), 'PX', ttl)
+end
+return {level, tat + interval - now}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitAlgorithm {
+    FixedWindow,
+    TokenBucket,
+}
+
+impl RateLimitAlgorithm {
+    pub fn parse(algorithm: &str) -> Option<Self> {
+        match algorithm {
+            "fixed_window" => Some(RateLimitAlgorithm::FixedWindow),
+            "token_bucket" => Some(RateLimitAlgorithm::TokenBucket),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of counting a request against a limit
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitUsage {
+    /// Requests in the window, or tokens in use, including this one
+    pub count: isize,
+    /// Until the whole limit is available again
+    pub reset: Duration,
+    /// Until the next request is allowed, zero when this one was
+    pub retry_after: Duration,
+}
+
+impl RateLimitUsage {
+    fn fixed_window(count: isize, reset: Duration, limit: isize) -> Self {
+        Self {
+            count,
+            reset,
+            retry_after: if count > limit { reset } else { Duration::ZERO },
+        }
+    }
+
+    /// `ahead` is how far the arrival time including this request is ahead of now
+    fn token_bucket(level: isize, ahead: Duration, bucket: &TokenBucket) -> Self {
+        if level <= bucket.burst as isize {
+            return Self {
+                count: level,
+                reset: ahead,
+                retry_after: Duration::ZERO,
+            };
+        }
+        Self {
+            count: level,
+            reset: ahead.saturating_sub(bucket.emission_interval),
+            retry_after: ahead.saturating_sub(bucket.emission_interval * bucket.burst),
+        }
+    }
+
+    pub fn remaining(&self, limit: isize) -> isize {
+        (limit - self.count).max(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitHeaderStyle {
+    /// RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset
+    Ietf,
+    /// X-RateLimit-Limit, X-RateLimit-Remaining and X-RateLimit-Reset
+    X,
+    None,
+}
+
+impl RateLimitHeaderStyle {
+    pub fn parse(style: &str) -> Option<Self> {
+        match style {
+            "ietf" => Some(RateLimitHeaderStyle::Ietf),
+            "x" => Some(RateLimitHeaderStyle::X),
+            "none" => Some(RateLimitHeaderStyle::None),
+            _ => None,
+        }
+    }
+}
+
+/// The limit a request was counted against, reported to the client
+#[derive(Debug, Clone)]
+pub struct RateLimitStatus {
+    /// "application", "default", "route", "quota" or "concurrency"
+    pub scope: &'static str,
+    /// The application id or route name
+    pub name: String,
+    pub limit: isize,
+    pub usage: RateLimitUsage,
+}
+
+impl RateLimitStatus {
+    pub fn remaining(&self) -> isize {
+        self.usage.remaining(self.limit)
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.usage.count > self.limit
+    }
+
+    pub fn headers(&self, style: RateLimitHeaderStyle) -> Vec<(&'static str, String)> {
+        let names = match style {
+            RateLimitHeaderStyle::Ietf => {
+                ["RateLimit-Limit", "RateLimit-Remaining", "RateLimit-Reset"]
+            }
+            RateLimitHeaderStyle::X => [
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "X-RateLimit-Reset",
+            ],
+            RateLimitHeaderStyle::None => return vec![],
+        };
+        let values = [
+            self.limit.to_string(),
+            self.remaining().to_string(),
+            ceil_seconds(self.usage.reset).to_string(),
+        ];
+        names.into_iter().zip(values).collect()
+    }
+
+    /// Seconds until a retry may succeed, at least one
+    pub fn retry_after(&self) -> u64 {
+        ceil_seconds(self.usage.retry_after).max(1)
+    }
+
+    /// The JSON body of the 429 response
+    pub fn body(&self) -> String {
+        serde_json::json!({
+            "error": "rate_limit_exceeded",
+            "message": format!("{} rate limit exceeded", self.scope),
+            "scope": self.scope,
+            "name": self.name,
+            "limit": self.limit,
+            "retry_after": self.retry_after(),
+        })
+        .to_string()
+    }
+}
+
+fn ceil_seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
+}
+
+enum Limits {
+    /// The windows of `Rate` start when it is created
+    FixedWindow(Rate, Duration, Instant),
+    TokenBucket(TokenBucket),
+}
 
 pub struct RateLimiter {
-    limits: Rate,
+    limits: Limits,
     max_req_per_second: u32,
+    redis: Option<Arc<RedisRateLimit>>,
 }
 
 impl RateLimiter {
-    pub fn new(limits: Rate, max_req_per_second: u32) -> Self {
+    pub fn new(interval: Duration, max_req_per_second: u32) -> Self {
         Self {
-            limits,
+            limits: Limits::FixedWindow(Rate::new(interval), interval, Instant::now()),
             max_req_per_second,
+            redis: None,
         }
     }
 
-    pub fn increase(&self, key: &str) -> isize {
-        self.limits.observe(&key, 1)
+    /// `rate` requests are refilled every `period`, up to `burst` at once
+    pub fn token_bucket(rate: u32, burst: u32, period: Duration) -> Self {
+        Self {
+            limits: Limits::TokenBucket(TokenBucket::new(rate, burst, period)),
+            max_req_per_second: burst,
+            redis: None,
+        }
+    }
+
+    /// Share the limit with other gateway instances, the local limit is
+    /// used while redis is unreachable
+    pub fn with_redis(mut self, redis: Arc<RedisRateLimit>) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    /// Count a request for `key`, the request is over the limit when the
+    /// count exceeds `max_req_per_second`
+    pub async fn increase(&self, key: &str) -> RateLimitUsage {
+        if let Some(redis) = &self.redis {
+            match redis
+                .increase(key, &self.limits, self.max_req_per_second())
+                .await
+            {
+                Ok(usage) => return usage,
+                Err(e) => warn!("Redis rate limit failed, limiting locally: {:?}", e),
+            }
+        }
+        match &self.limits {
+            Limits::FixedWindow(rate, interval, started) => {
+                let elapsed = started.elapsed().as_nanos() % interval.as_nanos().max(1);
+                let reset = interval.saturating_sub(Duration::from_nanos(elapsed as u64));
+                RateLimitUsage::fixed_window(
+                    rate.observe(&key, 1),
+                    reset,
+                    self.max_req_per_second(),
+                )
+            }
+            Limits::TokenBucket(bucket) => {
+                let (level, ahead) = bucket.acquire(key);
+                RateLimitUsage::token_bucket(level, ahead, bucket)
+            }
+        }
     }
 
     pub fn max_req_per_second(&self) -> isize {
         self.max_req_per_second as isize
     }
 
+    /// The rate of the last window, or the tokens in use of a token bucket
     pub fn rate(&self, key: &str) -> f64 {
-        self.limits.rate(&key)
+        match &self.limits {
+            Limits::FixedWindow(rate, ..) => rate.rate(&key),
+            Limits::TokenBucket(bucket) => bucket.level(key) as f64,
+        }
+    }
+}
+
+/// A limit counted separately for every client identified by `key`
+pub struct RateLimitPolicy {
+    pub key: RateLimitKey,
+    pub limiter: RateLimiter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum KeyPart {
+    /// The client address, grouped into networks of the prefix lengths
+    Ip {
+        v4: u8,
+        v6: u8,
+    },
+    Header(String),
+    /// A claim of the JWT verified by the route's jwt policy
+    Claim(String),
+    AppId,
+}
+
+/// Identifies the client a limit is counted for, the parts of a composite
+/// key are joined with "|". An empty key counts every request together.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitKey(Vec<KeyPart>);
+
+impl RateLimitKey {
+    pub fn client_ip() -> Self {
+        Self(vec![KeyPart::Ip { v4: 32, v6: 128 }])
+    }
+
+    /// Parse key parts, e.g., "ip", "ip/24" (IPv6 clients by /64), "ip/24/48",
+    /// "header:X-User-Id", "claim:sub" or "app_id"
+    pub fn parse(parts: &[String]) -> anyhow::Result<Self> {
+        parts
+            .iter()
+            .map(|part| {
+                let parsed = match part.split_once(':') {
+                    Some(("header", name)) => Some(KeyPart::Header(name.to_string())),
+                    Some(("claim", name)) => Some(KeyPart::Claim(name.to_string())),
+                    Some(_) => None,
+                    None if part == "app_id" => Some(KeyPart::AppId),
+                    None => {
+                        let mut prefixes = part.split('/');
+                        match (prefixes.next(), prefixes.next(), prefixes.next()) {
+                            (Some("ip"), None, None) => Some(KeyPart::Ip { v4: 32, v6: 128 }),
+                            (Some("ip"), Some(v4), v6) => {
+                                let v4 = v4.parse().ok().filter(|p| *p <= 32);
+                                let v6 =
+                                    v6.map_or(Some(64), |p| p.parse().ok().filter(|p| *p <= 128));
+                                v4.zip(v6).map(|(v4, v6)| KeyPart::Ip { v4, v6 })
+                            }
+                            _ => None,
+                        }
+                    }
+                };
+                parsed.ok_or(anyhow::anyhow!("invalid rate limit key: {}", part))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    /// Whether the key needs the claims of a verified JWT
+    pub fn uses_claims(&self) -> bool {
+        self.0.iter().any(|part| matches!(part, KeyPart::Claim(_)))
+    }
+
+    pub fn extract(
+        &self,
+        session: &Session,
+        client: Option<IpAddr>,
+        app_id: Option<&str>,
+        claims: Option<&serde_json::Value>,
+    ) -> String {
+        self.0
+            .iter()
+            .map(|part| {
+                let value = match part {
+                    KeyPart::Ip { v4, v6 } => client.map(|ip| mask_ip(ip, *v4, *v6)),
+                    KeyPart::Header(name) => session
+                        .get_header(name.as_str())
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| v.to_string()),
+                    KeyPart::Claim(name) => claims.and_then(|claims| claim_value(claims, name)),
+                    KeyPart::AppId => app_id.map(|v| v.to_string()),
+                };
+                value.unwrap_or(KEY_PART_MISSING.to_string())
+            })
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+}
+
+fn mask_ip(ip: IpAddr, v4: u8, v6: u8) -> String {
+    let prefix = if ip.is_ipv4() { v4 } else { v6 };
+    match IpNet::new(ip, prefix) {
+        Ok(net) if prefix < net.max_prefix_len() => net.trunc().to_string(),
+        _ => ip.to_string(),
+    }
+}
+
+fn claim_value(claims: &serde_json::Value, claim: &str) -> Option<String> {
+    match claims.get(claim)? {
+        serde_json::Value::String(v) => Some(v.clone()),
+        serde_json::Value::Null => None,
+        v => Some(v.to_string()),
+    }
+}
+
+/// A token bucket implemented as GCRA, only the theoretical arrival time of
+/// the next request is kept per key.
+struct TokenBucket {
+    emission_interval: Duration,
+    burst: u32,
+    tat: Mutex<HashMap<String, Instant>>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32, period: Duration) -> Self {
+        Self {
+            emission_interval: period / rate.max(1),
+            burst,
+            tat: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token, returning the tokens in use including this request and
+    /// how far the arrival time is ahead of now. The bucket is left untouched
+    /// when the request is over the burst.
+    fn acquire(&self, key: &str) -> (isize, Duration) {
+        let now = Instant::now();
+        let mut tat = self.tat.lock().unwrap();
+        let next = tat.get(key).map_or(now, |t| (*t).max(now)) + self.emission_interval;
+        let level = self.tokens(next - now);
+        if level <= self.burst as u128 {
+            if tat.len() >= TOKEN_BUCKET_PRUNE_SIZE {
+                tat.retain(|_, t| *t > now);
+            }
+            tat.insert(key.to_string(), next);
+        }
+        (level as isize, next - now)
+    }
+
+    fn level(&self, key: &str) -> u128 {
+        let now = Instant::now();
+        let tat = self.tat.lock().unwrap();
+        tat.get(key)
+            .map_or(0, |t| self.tokens(t.saturating_duration_since(now)))
+    }
+
+    fn tokens(&self, ahead: Duration) -> u128 {
+        ahead
+            .as_nanos()
+            .div_ceil(self.emission_interval.as_nanos().max(1))
+    }
+}
+
+/// A redis connection shared by the distributed rate limiters
+pub struct RedisRateLimit {
+    client: redis::Client,
+    key_prefix: String,
+    connection: OnceCell<ConnectionManager>,
+    retry_at: Mutex<Option<Instant>>,
+    fixed_window: Script,
+    token_bucket: Script,
+}
+
+impl RedisRateLimit {
+    pub fn new(url: &str, key_prefix: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            key_prefix: key_prefix.to_string(),
+            connection: OnceCell::new(),
+            retry_at: Mutex::new(None),
+            fixed_window: Script::new(FIXED_WINDOW_SCRIPT),
+            token_bucket: Script::new(TOKEN_BUCKET_SCRIPT),
+        })
+    }
+
+    async fn increase(
+        &self,
+        key: &str,
+        limits: &Limits,
+        limit: isize,
+    ) -> anyhow::Result<RateLimitUsage> {
+        if let Some(retry_at) = *self.retry_at.lock().unwrap()
+            && Instant::now() < retry_at
+        {
+            anyhow::bail!("redis unavailable");
+        }
+
+        let result = tokio::time::timeout(REDIS_TIMEOUT, self.eval(key, limits, limit)).await;
+        let result = result.unwrap_or_else(|_| Err(anyhow::anyhow!("redis timeout")));
+        *self.retry_at.lock().unwrap() = result
+            .as_ref()
+            .err()
+            .map(|_| Instant::now() + REDIS_RETRY_INTERVAL);
+        result
+    }
+
+    async fn eval(
+        &self,
+        key: &str,
+        limits: &Limits,
+        limit: isize,
+    ) -> anyhow::Result<RateLimitUsage> {
+        let mut connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(REDIS_TIMEOUT)
+                    .set_response_timeout(REDIS_TIMEOUT)
+                    .set_number_of_retries(1);
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await?
+            .clone();
+
+        let key = format!("{}{}", self.key_prefix, key);
+        let usage = match limits {
+            Limits::FixedWindow(_, interval, _) => {
+                let (count, ttl): (isize, i64) = self
+                    .fixed_window
+                    .key(key)
+                    .arg(interval.as_millis() as u64)
+                    .invoke_async(&mut connection)
+                    .await?;
+                let reset = Duration::from_millis(ttl.max(0) as u64);
+                RateLimitUsage::fixed_window(count, reset, limit)
+            }
+            Limits::TokenBucket(bucket) => {
+                let (level, ahead): (isize, i64) = self
+                    .token_bucket
+                    .key(key)
+                    .arg(bucket.emission_interval.as_micros().max(1) as u64)
+                    .arg(bucket.burst)
+                    .invoke_async(&mut connection)
+                    .await?;
+                let ahead = Duration::from_micros(ahead.max(0) as u64);
+                RateLimitUsage::token_bucket(level, ahead, bucket)
+            }
+        };
+        Ok(usage)
+    }
+}
+
+/// Bounds the requests in flight at once, calls over the limit wait up to
+/// `queue_timeout` for a slot
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    limit: u32,
+    queue_timeout: Duration,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: u32, queue_timeout: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            limit,
+            queue_timeout,
+        }
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// The limit and queue timeout of `update` on the slots of this limit,
+    /// so requests in flight still count after the application changed
+    pub fn resized(&self, update: &ConcurrencyLimit) -> Self {
+        let semaphore = self.semaphore.clone();
+        if update.limit > self.limit {
+            semaphore.add_permits((update.limit - self.limit) as usize);
+        } else if update.limit < self.limit {
+            // slots in use are taken away once they are released
+            let excess = self.limit - update.limit;
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+        Self {
+            semaphore,
+            limit: update.limit,
+            queue_timeout: update.queue_timeout,
+        }
+    }
+
+    /// A slot that is released when the permit is dropped, `None` when no
+    /// slot was freed within the queue timeout
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if self.queue_timeout.is_zero() {
+            return None;
+        }
+        tokio::time::timeout(self.queue_timeout, self.semaphore.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        // a token every 100ms, bursts of 3
+        let rl = RateLimiter::token_bucket(10, 3, Duration::from_secs(1));
+
+        for _ in 0..3 {
+            assert!(rl.increase("app").await.count <= rl.max_req_per_second());
+        }
+        assert!(rl.increase("app").await.count > rl.max_req_per_second());
+        assert!(rl.increase("other").await.count <= rl.max_req_per_second());
+
+        let usage = rl.increase("app").await;
+        assert!(usage.count > rl.max_req_per_second());
+        assert_eq!(usage.remaining(rl.max_req_per_second()), 0);
+        assert!(usage.retry_after > Duration::ZERO);
+        assert!(usage.retry_after <= Duration::from_millis(100));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(rl.increase("app").await.count <= rl.max_req_per_second());
+        assert!(rl.increase("app").await.count > rl.max_req_per_second());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let limit = ConcurrencyLimit::new(1, Duration::ZERO);
+        let permit = limit.acquire().await;
+        assert!(permit.is_some());
+        assert!(limit.acquire().await.is_none());
+        drop(permit);
+        assert!(limit.acquire().await.is_some());
+
+        let limit = Arc::new(ConcurrencyLimit::new(1, Duration::from_millis(500)));
+        let permit = limit.acquire().await.unwrap();
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(permit);
+        assert!(queued.await.unwrap());
+    }
+
+    #[test]
+    fn test_rate_limit_key() {
+        let key = |parts: &[&str]| {
+            RateLimitKey::parse(&parts.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(key(&["ip"]).unwrap(), RateLimitKey::client_ip());
+        assert_eq!(
+            key(&["ip/24", "header:X-User"]).unwrap(),
+            RateLimitKey(vec![
+                KeyPart::Ip { v4: 24, v6: 64 },
+                KeyPart::Header("X-User".to_string())
+            ])
+        );
+        assert!(key(&["ip/33"]).is_err());
+        assert!(key(&["cookie:session"]).is_err());
+
+        assert_eq!(mask_ip("10.1.2.3".parse().unwrap(), 24, 64), "10.1.2.0/24");
+        assert_eq!(
+            mask_ip("2001:db8::1".parse().unwrap(), 24, 128),
+            "2001:db8::1"
+        );
+
+        assert!(key(&["ip", "claim:sub"]).unwrap().uses_claims());
+        assert!(!key(&["ip", "header:X-User"]).unwrap().uses_claims());
+
+        let claims = serde_json::json!({"sub": "alice", "tier": 2, "exp": null});
+        assert_eq!(claim_value(&claims, "sub").as_deref(), Some("alice"));
+        assert_eq!(claim_value(&claims, "tier").as_deref(), Some("2"));
+        assert_eq!(claim_value(&claims, "exp"), None);
+    }
+
+    #[tokio::test]
+    async fn test_redis_fallback() {
+        let redis = RedisRateLimit::new("redis://127.0.0.1:1", "test:").unwrap();
+        let rl = RateLimiter::new(Duration::from_secs(60), 2).with_redis(Arc::new(redis));
+
+        assert_eq!(rl.increase("app").await.count, 1);
+        assert_eq!(rl.increase("app").await.count, 2);
+        assert!(rl.increase("app").await.count > rl.max_req_per_second());
+    }
+
+    /// Requires a local redis-server, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_shared_limit() {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let prefix = format!("test:{}:", std::process::id());
+        let redis = Arc::new(RedisRateLimit::new(&url, &prefix).unwrap());
+        // two gateway instances sharing one limit
+        let a = RateLimiter::token_bucket(1, 2, Duration::from_secs(60)).with_redis(redis.clone());
+        let b = RateLimiter::token_bucket(1, 2, Duration::from_secs(60)).with_redis(redis.clone());
+
+        assert!(a.increase("app").await.count <= a.max_req_per_second());
+        assert!(b.increase("app").await.count <= b.max_req_per_second());
+        assert!(a.increase("app").await.count > a.max_req_per_second());
+        assert!(redis.retry_at.lock().unwrap().is_none());
+
+        let a = RateLimiter::new(Duration::from_secs(60), 1).with_redis(redis.clone());
+        let b = RateLimiter::new(Duration::from_secs(60), 1).with_redis(redis.clone());
+        assert_eq!(a.increase("window").await.count, 1);
+        assert_eq!(b.increase("window").await.count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_resized() {
+        let limit = ConcurrencyLimit::new(2, Duration::ZERO);
+        let first = limit.acquire().await.unwrap();
+        let _second = limit.acquire().await.unwrap();
+
+        // the slots in flight count against the updated limit
+        let grown = limit.resized(&ConcurrencyLimit::new(3, Duration::ZERO));
+        let third = grown.acquire().await.unwrap();
+        assert!(grown.acquire().await.is_none());
+
+        let shrunk = grown.resized(&ConcurrencyLimit::new(1, Duration::ZERO));
+        drop(first);
+        drop(third);
+        tokio::task::yield_now().await;
+        assert_eq!(shrunk.limit(), 1);
+        assert!(shrunk.acquire().await.is_none());
     }
 }
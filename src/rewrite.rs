@@ -0,0 +1,220 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::http::{header, uri::PathAndQuery, Uri};
+use pingora::http::RequestHeader;
+
+use crate::config::GatewayQueryRewriteConfig;
+
+/// Query parameters removed, renamed and added, in that order. Added
+/// parameters are sorted by name.
+#[derive(Default)]
+pub struct QueryRewrite {
+    remove: Vec<String>,
+    rename: HashMap<String, String>,
+    add: Vec<(String, String)>,
+}
+
+impl QueryRewrite {
+    pub fn new(config: &GatewayQueryRewriteConfig) -> Self {
+        let mut add = config
+            .add
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        add.sort();
+        Self {
+            remove: config.remove.clone().unwrap_or_default(),
+            rename: config.rename.clone().unwrap_or_default(),
+            add,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.rename.is_empty() && self.add.is_empty()
+    }
+
+    /// The rewritten query, `None` when no parameter is left
+    fn apply(&self, query: Option<&str>) -> Option<String> {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        let params = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(name, _)| !self.remove.iter().any(|r| r == name));
+        for (name, value) in params {
+            let name = self.rename.get(name.as_ref()).map_or(name, |n| n.into());
+            serializer.append_pair(&name, &value);
+        }
+        for (name, value) in &self.add {
+            serializer.append_pair(name, value);
+        }
+        Some(serializer.finish()).filter(|query| !query.is_empty())
+    }
+}
+
+/// Changes to the upstream request besides the path regex
+pub struct UpstreamRewrite {
+    /// Sent as the Host header and the TLS server name
+    host: Option<String>,
+    /// Keep the Host of the client unless `host` is set, otherwise send the
+    /// upstream address
+    preserve_host: bool,
+    tls: bool,
+    path_prefix: Option<String>,
+    query: QueryRewrite,
+}
+
+impl UpstreamRewrite {
+    pub fn new(
+        host: Option<String>,
+        preserve_host: bool,
+        tls: bool,
+        path_prefix: Option<String>,
+        query: QueryRewrite,
+    ) -> anyhow::Result<Self> {
+        let path_prefix = path_prefix.map(|prefix| prefix.trim_end_matches('/').to_string());
+        if let Some(prefix) = &path_prefix {
+            anyhow::ensure!(
+                prefix.is_empty() || prefix.starts_with('/'),
+                "path_prefix must start with /"
+            );
+            anyhow::ensure!(!prefix.contains(['?', '#']), "path_prefix must be a path");
+        }
+        // the prefix and the added parameters are in every rewritten URI
+        let sample = format!(
+            "{}/?{}",
+            path_prefix.as_deref().unwrap_or_default(),
+            query.apply(None).unwrap_or_default()
+        );
+        PathAndQuery::from_str(&sample)?;
+        Ok(Self {
+            host,
+            preserve_host,
+            tls,
+            path_prefix,
+            query,
+        })
+    }
+
+    /// Whether the upstream is reached over TLS
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// The TLS server name, the configured host or the one of the client
+    pub fn sni(&self, req: &RequestHeader) -> String {
+        let host = match &self.host {
+            Some(host) => host.as_str(),
+            None => req
+                .headers
+                .get(header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .or(req.uri.host())
+                .unwrap_or_default(),
+        };
+        // a port belongs to the address, not the server name
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() && !name.ends_with(']') => {
+                name.to_string()
+            }
+            _ => host.to_string(),
+        }
+    }
+
+    pub fn apply(&self, req: &mut RequestHeader, upstream: Option<&str>) -> pingora::Result<()> {
+        match (&self.host, self.preserve_host) {
+            (Some(host), _) => req.insert_header(header::HOST, host)?,
+            (None, false) => {
+                if let Some(upstream) = upstream {
+                    req.insert_header(header::HOST, upstream)?;
+                }
+            }
+            (None, true) => {}
+        }
+
+        if self.path_prefix.is_none() && self.query.is_empty() {
+            return Ok(());
+        }
+        let path = match &self.path_prefix {
+            Some(prefix) => format!("{}{}", prefix, req.uri.path()),
+            None => req.uri.path().to_string(),
+        };
+        let path_and_query = match self.query.is_empty() {
+            true => req.uri.query().map(|q| q.to_string()),
+            false => self.query.apply(req.uri.query()),
+        }
+        .map_or(path.clone(), |query| format!("{}?{}", path, query));
+
+        // keep the original URI rather than lose its path
+        let Ok(path_and_query) = PathAndQuery::from_str(&path_and_query) else {
+            return Ok(());
+        };
+        let mut uri = req.uri.clone().into_parts();
+        uri.path_and_query = Some(path_and_query);
+        if let Ok(uri) = Uri::from_parts(uri) {
+            req.set_uri(uri);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upstream_rewrite() {
+        let query = QueryRewrite::new(&GatewayQueryRewriteConfig {
+            add: Some(HashMap::from([
+                ("source".to_string(), "gateway".to_string()),
+                ("api".to_string(), "v1".to_string()),
+            ])),
+            remove: Some(vec!["debug".to_string()]),
+            rename: Some(HashMap::from([("q".to_string(), "query".to_string())])),
+        });
+        let rewrite = UpstreamRewrite::new(
+            Some("backend.internal".to_string()),
+            true,
+            true,
+            Some("/v1/".to_string()),
+            query,
+        )
+        .unwrap();
+        let mut req = RequestHeader::build("GET", b"/orders?q=a+b&debug=1&page=2", None).unwrap();
+        req.insert_header(header::HOST, "api.example.com:8443")
+            .unwrap();
+        rewrite.apply(&mut req, Some("10.0.0.1:80")).unwrap();
+        assert_eq!(
+            req.uri.to_string(),
+            "/v1/orders?query=a+b&page=2&api=v1&source=gateway"
+        );
+        assert_eq!(req.headers[header::HOST], "backend.internal");
+        assert_eq!(rewrite.sni(&req), "backend.internal");
+
+        let rewrite =
+            UpstreamRewrite::new(None, false, false, None, QueryRewrite::default()).unwrap();
+        let mut req = RequestHeader::build("GET", b"/orders?debug=1", None).unwrap();
+        req.insert_header(header::HOST, "api.example.com:8443")
+            .unwrap();
+        assert_eq!(rewrite.sni(&req), "api.example.com");
+        rewrite.apply(&mut req, Some("10.0.0.1:80")).unwrap();
+        assert_eq!(req.uri.to_string(), "/orders?debug=1");
+        assert_eq!(req.headers[header::HOST], "10.0.0.1:80");
+
+        let only_removed = QueryRewrite::new(&GatewayQueryRewriteConfig {
+            add: None,
+            remove: Some(vec!["debug".to_string()]),
+            rename: None,
+        });
+        assert_eq!(only_removed.apply(Some("debug=1")), None);
+
+        for prefix in ["v1", "/v 1", "/v1?x"] {
+            let rewrite = UpstreamRewrite::new(
+                None,
+                true,
+                false,
+                Some(prefix.to_string()),
+                QueryRewrite::default(),
+            );
+            assert!(rewrite.is_err(), "{}", prefix);
+        }
+    }
+}
@@ -7,7 +7,12 @@ use pingora::{
 };
 use tracing::info;
 
-use crate::{lb::GatewayLoadBalancer, proxy::ProxyCmd};
+use crate::{
+    acl::IpAccessScope,
+    lb::{GatewayLoadBalancer, GatewayLoadBalancerOptions},
+    proxy::ProxyCmd,
+    stream::GatewayStreamListener,
+};
 
 pub type PingoraBackgroundService = Box<Arc<dyn BackgroundService + Send + Sync + 'static>>;
 
@@ -143,26 +148,8 @@ impl Service for ProxyService {
                 }
                 Some(v) = self.cmd_rev.recv() => {
                     match v {
-                        ProxyCmd::Add(key, options) => {
-                            let lb = Arc::new(GatewayLoadBalancer::new(&key, options));
-                            let mut reoutes = crate::store::routes().write().await;
-                            reoutes.insert(key.to_string(), Arc::clone(&lb));
-
-                            let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Add(
-                                format!("{}_hc", key),
-                                Box::new(lb),
-                            )).await;
-                            info!("add route: {}", key);
-                        }
-                        ProxyCmd::Remove(key) => {
-                            let mut reoutes = crate::store::routes().write().await;
-                            reoutes.remove(&key);
-                            // try to remove health check
-                            let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Remove(
-                                format!("{}_hc", key)
-                            )).await;
-                            info!("remove route: {}", key);
-                        }
+                        ProxyCmd::Add(key, options) => add_route(key, *options).await,
+                        ProxyCmd::Remove(key) => remove_route(key).await,
                     }
                 }
             }
@@ -177,3 +164,74 @@ impl Service for ProxyService {
         Some(1)
     }
 }
+
+async fn add_route(key: String, options: GatewayLoadBalancerOptions) {
+    let lb = Arc::new(GatewayLoadBalancer::new(&key, options));
+    if let Some(listen) = lb.listen() {
+        crate::store::routes().write().await.remove(&key);
+        let previous = crate::store::stream_routes()
+            .write()
+            .await
+            .insert(key.to_string(), Arc::clone(&lb));
+        // a route moved to another address leaves its old listener behind
+        if let Some(previous) = previous.as_ref().and_then(|lb| lb.listen())
+            && previous != listen
+        {
+            close_unused_listener(previous).await;
+        }
+        // the listener is shared by all tcp routes on the same address
+        let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Add(
+            format!("{}_listener", listen),
+            Box::new(Arc::new(GatewayStreamListener::new(listen))),
+        ))
+        .await;
+    } else {
+        // the route may have been a tcp route before
+        remove_stream_route(&key).await;
+        let mut reoutes = crate::store::routes().write().await;
+        reoutes.insert(key.to_string(), Arc::clone(&lb));
+    }
+
+    // replace the health check of a route added before
+    let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Remove(format!("{}_hc", key)))
+        .await;
+    let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Add(
+        format!("{}_hc", key),
+        Box::new(lb),
+    ))
+    .await;
+    info!("add route: {}", key);
+}
+
+async fn remove_route(key: String) {
+    crate::store::routes().write().await.remove(&key);
+    remove_stream_route(&key).await;
+    crate::store::ip_access().remove(&IpAccessScope::Route(key.clone()));
+    // try to remove health check
+    let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Remove(format!("{}_hc", key)))
+        .await;
+    info!("remove route: {}", key);
+}
+
+/// Removes a tcp route and closes its listener once no other route uses it.
+async fn remove_stream_route(key: &str) {
+    let removed = crate::store::stream_routes().write().await.remove(key);
+    if let Some(listen) = removed.as_ref().and_then(|lb| lb.listen()) {
+        close_unused_listener(listen).await;
+    }
+}
+
+async fn close_unused_listener(listen: &str) {
+    let in_use = crate::store::stream_routes()
+        .read()
+        .await
+        .values()
+        .any(|lb| lb.listen() == Some(listen));
+    if !in_use {
+        let _ = crate::store::globalbackground_cmd(GlobalBackgroundCmd::Remove(format!(
+            "{}_listener",
+            listen
+        )))
+        .await;
+    }
+}
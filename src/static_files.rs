@@ -0,0 +1,347 @@
+use std::{
+    fs::Metadata,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header, Method};
+use bytes::{Bytes, BytesMut};
+use percent_encoding::percent_decode_str;
+use pingora::http::ResponseHeader;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::error;
+
+use crate::{action::ActionResponse, config::GatewayStaticFilesConfig};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file found under the root
+struct StaticFile {
+    path: PathBuf,
+    meta: Metadata,
+}
+
+/// Serves the files of a directory like a plain web server
+pub struct StaticFiles {
+    /// Canonical, so resolved files can be checked to stay below it
+    root: PathBuf,
+    index: Vec<String>,
+    spa_fallback: bool,
+}
+
+impl StaticFiles {
+    pub fn new(config: &GatewayStaticFilesConfig) -> anyhow::Result<Self> {
+        let root = std::fs::canonicalize(&config.root)?;
+        if !root.is_dir() {
+            anyhow::bail!("{} is no directory", config.root);
+        }
+        Ok(Self {
+            root,
+            index: config
+                .index
+                .clone()
+                .unwrap_or_else(|| vec!["index.html".to_string()]),
+            spa_fallback: config.spa_fallback.unwrap_or(false),
+        })
+    }
+
+    /// The file or directory of a url path, `None` for paths leaving the
+    /// root, also through symlinks
+    async fn resolve(&self, path: &str) -> Option<(PathBuf, Metadata)> {
+        let path = percent_decode_str(path).decode_utf8().ok()?;
+        let mut file = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                s if s.contains(['\\', '\0']) => return None,
+                s => file.push(s),
+            }
+        }
+        let file = tokio::fs::canonicalize(&file).await.ok()?;
+        if !file.starts_with(&self.root) {
+            return None;
+        }
+        let meta = tokio::fs::metadata(&file).await.ok()?;
+        Some((file, meta))
+    }
+
+    async fn index_file(&self, dir: &Path) -> Option<StaticFile> {
+        for index in &self.index {
+            let path = dir.join(index);
+            if let Ok(meta) = tokio::fs::metadata(&path).await
+                && meta.is_file()
+            {
+                return Some(StaticFile { path, meta });
+            }
+        }
+        None
+    }
+
+    /// `path` is the request path after the route rewrite, redirects to
+    /// directories use the path the client sent
+    pub async fn serve(
+        &self,
+        response: &mut ActionResponse<'_>,
+        path: &str,
+    ) -> pingora::Result<()> {
+        let method = &response.req_header().method;
+        if method != Method::GET && method != Method::HEAD {
+            return response.error(405, "only GET and HEAD are allowed").await;
+        }
+
+        let file = match self.resolve(path).await {
+            Some((path, meta)) if meta.is_file() => Some(StaticFile { path, meta }),
+            Some((dir, _)) if !path.ends_with('/') && self.index_file(&dir).await.is_some() => {
+                // relative links of an index file need the trailing slash
+                let uri = &response.req_header().uri;
+                let location = match uri.query() {
+                    Some(query) => format!("{}/?{}", uri.path(), query),
+                    None => format!("{}/", uri.path()),
+                };
+                let mut header = ResponseHeader::build(301, Some(2))?;
+                header.insert_header(header::LOCATION, location)?;
+                header.insert_header(header::CONTENT_LENGTH, "0")?;
+                return response.write_header(header, true).await;
+            }
+            Some((dir, _)) => self.index_file(&dir).await,
+            None => None,
+        };
+        // client side routes of a single page application have no extension
+        let client_route = !path.rsplit('/').next().unwrap_or_default().contains('.');
+        let file = match file {
+            Some(file) => file,
+            None if self.spa_fallback && client_route => match self.index_file(&self.root).await {
+                Some(file) => file,
+                None => return response.error(404, "file not found").await,
+            },
+            None => return response.error(404, "file not found").await,
+        };
+        self.send(response, file).await
+    }
+
+    async fn send(
+        &self,
+        response: &mut ActionResponse<'_>,
+        file: StaticFile,
+    ) -> pingora::Result<()> {
+        let len = file.meta.len();
+        let modified = file.meta.modified().unwrap_or(UNIX_EPOCH);
+        let modified_secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let etag = format!("\"{:x}-{:x}\"", modified_secs, len);
+        let last_modified = httpdate::fmt_http_date(modified);
+
+        let mut header = ResponseHeader::build(200, Some(8))?;
+        header.insert_header(header::ETAG, &etag)?;
+        header.insert_header(header::LAST_MODIFIED, &last_modified)?;
+        header.insert_header(header::ACCEPT_RANGES, "bytes")?;
+        let mime = mime_guess::from_path(&file.path).first_or_octet_stream();
+        // a stale index of a single page application references gone assets
+        if mime == mime_guess::mime::TEXT_HTML {
+            header.insert_header(header::CACHE_CONTROL, "no-cache")?;
+        }
+        header.insert_header(header::CONTENT_TYPE, mime.as_ref())?;
+
+        let req = response.req_header();
+        let request_header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        if not_modified(
+            request_header(header::IF_NONE_MATCH),
+            request_header(header::IF_MODIFIED_SINCE),
+            &etag,
+            modified_secs,
+        ) {
+            header.set_status(304)?;
+            header.remove_header(&header::CONTENT_TYPE);
+            return response.write_header(header, true).await;
+        }
+
+        // a range of an older version of the file would be corrupt
+        let if_range = request_header(header::IF_RANGE);
+        let range = match request_header(header::RANGE) {
+            Some(_) if if_range.is_some_and(|v| v != etag && v != last_modified) => None,
+            Some(range) => parse_range(range, len),
+            None => None,
+        };
+        let (start, end) = match range {
+            Some(Ok((start, end))) => {
+                header.set_status(206)?;
+                header.insert_header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                )?;
+                (start, end + 1)
+            }
+            Some(Err(())) => {
+                let mut header = ResponseHeader::build(416, Some(2))?;
+                header.insert_header(header::CONTENT_RANGE, format!("bytes */{}", len))?;
+                header.insert_header(header::CONTENT_LENGTH, "0")?;
+                return response.write_header(header, true).await;
+            }
+            None => (0, len),
+        };
+        header.insert_header(header::CONTENT_LENGTH, (end - start).to_string())?;
+
+        if response.req_header().method == Method::HEAD || start == end {
+            return response.write_header(header, true).await;
+        }
+        let mut reader = match open_at(&file.path, start).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!("Failed to read {:?}: {:?}", file.path, e);
+                return response.error(500, "failed to read file").await;
+            }
+        };
+        response.write_header(header, false).await?;
+        let mut remaining = end - start;
+        while remaining > 0 {
+            let mut chunk = BytesMut::zeroed(CHUNK_SIZE.min(remaining as usize));
+            let read = match reader.read(&mut chunk).await {
+                Ok(0) | Err(_) => {
+                    // the file shrank while it was sent, the length is a lie now
+                    return pingora::Error::e_explain(
+                        pingora::ErrorType::InternalError,
+                        "static file changed while sent",
+                    );
+                }
+                Ok(read) => read,
+            };
+            chunk.truncate(read);
+            remaining -= read as u64;
+            response
+                .write_body(Bytes::from(chunk), remaining == 0)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn open_at(path: &Path, start: u64) -> std::io::Result<tokio::fs::File> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(file)
+}
+
+/// Whether the client copy is current, by ETag before modification time
+fn not_modified(
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    etag: &str,
+    modified_secs: u64,
+) -> bool {
+    if let Some(tags) = if_none_match {
+        return tags.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+    if_modified_since
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .is_some_and(|since| since >= SystemTime::UNIX_EPOCH + Duration::from_secs(modified_secs))
+}
+
+/// The inclusive byte range of a single range request, `Err` when it is not
+/// satisfiable and `None` for anything else, which is served in full
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse::<u64>().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if len == 0 || range.0 >= len {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=500-5000", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=9-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = "\"5f5e100-3e8\"";
+        assert!(not_modified(Some(etag), None, etag, 100));
+        assert!(not_modified(
+            Some("\"a\", W/\"5f5e100-3e8\""),
+            None,
+            etag,
+            100
+        ));
+        assert!(!not_modified(
+            Some("\"a\""),
+            Some("Thu, 01 Jan 2099 00:00:00 GMT"),
+            etag,
+            100
+        ));
+        assert!(not_modified(
+            None,
+            Some("Thu, 01 Jan 1970 00:01:40 GMT"),
+            etag,
+            100
+        ));
+        assert!(!not_modified(
+            None,
+            Some("Thu, 01 Jan 1970 00:01:39 GMT"),
+            etag,
+            100
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_stays_in_root() {
+        let root = std::env::temp_dir().join(format!("gateway_static_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "<html>").unwrap();
+        std::fs::write(root.join("assets/app 1.js"), "app").unwrap();
+        let files = StaticFiles::new(&GatewayStaticFilesConfig {
+            root: root.to_str().unwrap().to_string(),
+            index: None,
+            spa_fallback: Some(true),
+        })
+        .unwrap();
+
+        let (path, _) = files.resolve("/assets/app%201.js").await.unwrap();
+        assert!(path.ends_with("assets/app 1.js"));
+        assert!(files.resolve("/assets").await.unwrap().1.is_dir());
+        assert!(files.resolve("/../etc/passwd").await.is_none());
+        assert!(files
+            .resolve("/assets/%2e%2e/%2e%2e/etc/passwd")
+            .await
+            .is_none());
+        assert!(files.resolve("/assets/..%5c..%5cetc").await.is_none());
+        assert!(files.resolve("/missing.js").await.is_none());
+
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        assert!(files.resolve("/etc/passwd").await.is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,18 +1,31 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
 };
 
 use bollard::secret::ContainerSummary;
+use pingora::cache::lock::CacheLock;
 use tokio::sync::{OnceCell, RwLock};
 
 use crate::{
-    lb::GatewayLoadBalancer, proxy::ProxyCmd, rate_limit::RateLimiter, service::GlobalBackgroundCmd,
+    acl::{IpAccessLists, RouteAcl},
+    cache::CacheStorage,
+    credentials::{Credentials, IssuedApiKeys, Nonces},
+    lb::GatewayLoadBalancer,
+    mtls::ClientCertificates,
+    proxy::ProxyCmd,
+    proxy_protocol::ProxyHeader,
+    quota::{Quota, QuotaStore},
+    rate_limit::{ConcurrencyLimit, RateLimitPolicy, RateLimiter, RedisRateLimit},
+    service::GlobalBackgroundCmd,
 };
 
 static PROXY_CMD: OnceCell<tokio::sync::mpsc::Sender<ProxyCmd>> = OnceCell::const_new();
 static ROUTES: LazyLock<RwLock<HashMap<String, Arc<GatewayLoadBalancer>>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
+static STREAM_ROUTES: LazyLock<RwLock<HashMap<String, Arc<GatewayLoadBalancer>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
 static GLOBALBACKGROUND_CMD: OnceCell<tokio::sync::mpsc::Sender<GlobalBackgroundCmd>> =
     OnceCell::const_new();
 static CONTAINERS: LazyLock<RwLock<Vec<ContainerSummary>>> =
@@ -22,6 +35,56 @@ static APPLICATIONS: LazyLock<RwLock<HashMap<String, Arc<GatewayApplication>>>>
 static DOCKER_CLIENT: LazyLock<Arc<bollard::Docker>> = LazyLock::new(|| {
     Arc::new(bollard::Docker::connect_with_defaults().expect("fail to connect to docker"))
 });
+static PROXY_PROTOCOL_HEADERS: LazyLock<RwLock<HashMap<std::net::SocketAddr, ProxyHeader>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+static REDIS_RATE_LIMIT: LazyLock<Option<Arc<RedisRateLimit>>> = LazyLock::new(|| {
+    let redis = config().redis.as_ref()?;
+    let key_prefix = redis.key_prefix.as_deref().unwrap_or("gateway:rate_limit:");
+    RedisRateLimit::new(&redis.url, key_prefix)
+        .inspect_err(|e| tracing::error!("Invalid redis url {}: {:?}", redis.url, e))
+        .ok()
+        .map(Arc::new)
+});
+static DEFAULT_RATE_LIMIT: LazyLock<Option<Arc<RateLimitPolicy>>> = LazyLock::new(|| {
+    let rate_limit = config().default_rate_limit.as_ref()?;
+    // checked at startup, an invalid policy never gets here
+    crate::app::default_rate_limit_policy(rate_limit)
+        .ok()
+        .map(Arc::new)
+});
+static QUOTAS: LazyLock<Arc<QuotaStore>> = LazyLock::new(|| {
+    let path = config()
+        .quota_file
+        .as_deref()
+        .unwrap_or("gateway_quota.json");
+    Arc::new(QuotaStore::load(path))
+});
+static ISSUED_API_KEYS: LazyLock<IssuedApiKeys> = LazyLock::new(|| {
+    let path = config()
+        .api_key_file
+        .as_deref()
+        .unwrap_or("gateway_api_keys.json");
+    IssuedApiKeys::load(path)
+});
+static HMAC_NONCES: LazyLock<Mutex<HashMap<String, Nonces>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static IP_ACCESS: LazyLock<IpAccessLists> = LazyLock::new(IpAccessLists::default);
+static CLIENT_CERTIFICATES: LazyLock<ClientCertificates> =
+    LazyLock::new(ClientCertificates::default);
+static MEMORY_CACHE: LazyLock<CacheStorage> = LazyLock::new(|| {
+    let max_size = config().cache.as_ref().and_then(|c| c.memory_max_size);
+    CacheStorage::memory(max_size.unwrap_or(64 << 20))
+});
+static DISK_CACHE: LazyLock<Option<CacheStorage>> = LazyLock::new(|| {
+    let disk = config().cache.as_ref()?.disk.as_ref()?;
+    CacheStorage::disk(&disk.path, disk.max_size.unwrap_or(1 << 30))
+        .inspect_err(|e| tracing::error!("Invalid disk cache {}: {:?}", disk.path, e))
+        .ok()
+});
+static CACHE_LOCK: LazyLock<CacheLock> = LazyLock::new(|| {
+    let timeout = config().cache.as_ref().and_then(|c| c.lock_timeout_seconds);
+    CacheLock::new(Duration::from_secs(timeout.unwrap_or(10)))
+});
 static CONFIG: OnceCell<crate::config::GatewayConfig> = OnceCell::const_new();
 
 pub fn docker_client() -> Arc<bollard::Docker> {
@@ -36,10 +99,65 @@ pub fn applications() -> &'static RwLock<HashMap<String, Arc<GatewayApplication>
     &APPLICATIONS
 }
 
+/// PROXY protocol headers keyed by the loopback address of the forwarded connection
+pub fn proxy_protocol_headers() -> &'static RwLock<HashMap<std::net::SocketAddr, ProxyHeader>> {
+    &PROXY_PROTOCOL_HEADERS
+}
+
+pub fn redis_rate_limit() -> Option<Arc<RedisRateLimit>> {
+    REDIS_RATE_LIMIT.clone()
+}
+
+/// The rate limit of callers without a known application id
+pub fn default_rate_limit() -> Option<Arc<RateLimitPolicy>> {
+    DEFAULT_RATE_LIMIT.clone()
+}
+
+pub fn quotas() -> Arc<QuotaStore> {
+    Arc::clone(&QUOTAS)
+}
+
+pub fn issued_api_keys() -> &'static IssuedApiKeys {
+    &ISSUED_API_KEYS
+}
+
+/// The nonces of the signed requests of an application, shared by every
+/// build of it
+pub fn hmac_nonces(app_id: &str) -> Nonces {
+    let mut nonces = HMAC_NONCES.lock().unwrap();
+    nonces.entry(app_id.to_string()).or_default().clone()
+}
+
+pub fn ip_access() -> &'static IpAccessLists {
+    &IP_ACCESS
+}
+
+/// Client certificates verified by the TLS listeners
+pub fn client_certificates() -> &'static ClientCertificates {
+    &CLIENT_CERTIFICATES
+}
+
+pub fn memory_cache() -> &'static CacheStorage {
+    &MEMORY_CACHE
+}
+
+pub fn disk_cache() -> Option<&'static CacheStorage> {
+    DISK_CACHE.as_ref()
+}
+
+/// Collapses concurrent cache misses of a response into one upstream request
+pub fn cache_lock() -> &'static CacheLock {
+    &CACHE_LOCK
+}
+
 pub fn routes() -> &'static RwLock<HashMap<String, Arc<GatewayLoadBalancer>>> {
     &ROUTES
 }
 
+pub fn stream_routes() -> &'static RwLock<HashMap<String, Arc<GatewayLoadBalancer>>> {
+    &STREAM_ROUTES
+}
+
 pub async fn proxy_cmd(cmd: ProxyCmd) -> anyhow::Result<()> {
     Ok(PROXY_CMD
         .get()
@@ -80,16 +198,62 @@ pub fn init_config(config: crate::config::GatewayConfig) {
 
 pub struct GatewayApplication {
     rate_limiter: RateLimiter,
+    quota: Option<Quota>,
+    concurrency: Option<ConcurrencyLimit>,
+    credentials: Credentials,
+    acl: Option<RouteAcl>,
 }
 
 impl GatewayApplication {
     pub fn new(rate_limiter: RateLimiter) -> Self {
-        Self { rate_limiter }
+        Self {
+            rate_limiter,
+            quota: None,
+            concurrency: None,
+            credentials: Credentials::default(),
+            acl: None,
+        }
+    }
+
+    pub fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: ConcurrencyLimit) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub fn with_acl(mut self, acl: RouteAcl) -> Self {
+        self.acl = Some(acl);
+        self
     }
 
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
     }
+
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref()
+    }
+
+    pub fn concurrency(&self) -> Option<&ConcurrencyLimit> {
+        self.concurrency.as_ref()
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn acl(&self) -> Option<&RouteAcl> {
+        self.acl.as_ref()
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,390 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use pingora::{
+    protocols::l4::{socket::SocketAddr, stream::Stream},
+    server::ShutdownWatch,
+    services::background::BackgroundService,
+};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tracing::{error, info};
+
+use crate::{
+    lb::GatewayLoadBalancer,
+    metrics::StreamingGuard,
+    proxy_protocol::{ProxyHeader, ProxyProtocolAcceptor, ProxyProtocolConnector},
+};
+
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(3);
+const SNI_PEEK_BUFFER: usize = 4096;
+const BIND_RETRY_MIN: Duration = Duration::from_secs(1);
+const BIND_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// Accepts raw tcp connections for every tcp route bound to `addr` and
+/// forwards them to the backends of the matching route.
+pub struct GatewayStreamListener {
+    addr: String,
+}
+
+impl GatewayStreamListener {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+
+    /// The proxy protocol settings shared by the routes of the listener,
+    /// looked up per connection so route updates apply at once
+    async fn proxy_protocol(&self) -> Option<Arc<ProxyProtocolAcceptor>> {
+        crate::store::stream_routes()
+            .read()
+            .await
+            .values()
+            .find(|lb| lb.listen() == Some(self.addr.as_str()))
+            .and_then(|lb| lb.proxy_protocol())
+    }
+
+    async fn find_route(&self, sni: Option<&str>) -> Option<Arc<GatewayLoadBalancer>> {
+        let routes = crate::store::stream_routes().read().await;
+        // an sni route wins over a catch-all route on the same listener
+        let mut fallback = None;
+        for lb in routes
+            .values()
+            .filter(|lb| lb.listen() == Some(self.addr.as_str()))
+        {
+            if sni.is_some() && lb.matches_sni(sni) && !lb.matches_sni(None) {
+                return Some(lb.clone());
+            }
+            if fallback.is_none() && lb.matches_sni(None) {
+                fallback = Some(lb.clone());
+            }
+        }
+        fallback
+    }
+
+    async fn needs_sni(&self) -> bool {
+        crate::store::stream_routes()
+            .read()
+            .await
+            .values()
+            .any(|lb| lb.listen() == Some(self.addr.as_str()) && !lb.matches_sni(None))
+    }
+
+    async fn handle(
+        &self,
+        mut downstream: TcpStream,
+        peer: std::net::SocketAddr,
+    ) -> anyhow::Result<()> {
+        let local = downstream.local_addr()?;
+        let header = match self.proxy_protocol().await {
+            Some(acceptor) => acceptor.accept(&mut downstream, peer, local).await?,
+            None => ProxyHeader {
+                source: Some(peer),
+                destination: Some(local),
+            },
+        };
+
+        let sni = if self.needs_sni().await {
+            peek_sni(&downstream).await
+        } else {
+            None
+        };
+
+        let lb = self
+            .find_route(sni.as_deref())
+            .await
+            .ok_or(anyhow::anyhow!(
+                "no tcp route on {} for sni {:?}",
+                self.addr,
+                sni
+            ))?;
+        let backend = lb
+            .lb()
+            .select(b"", 256)
+            .ok_or(anyhow::anyhow!("no healthy upstream for {}", lb.name()))?;
+
+        let mut upstream = match lb.upstream_proxy_protocol() {
+            Some(version) => {
+                ProxyProtocolConnector::new(version, header)
+                    .connect_stream(&backend.addr)
+                    .await?
+            }
+            None => connect_stream(&backend.addr).await?,
+        };
+        info!(
+            "tcp upstream peer is: {:?} --> {} --> {:?}",
+            header.source,
+            lb.name(),
+            backend
+        );
+
+        let _guard = StreamingGuard::new(lb.name(), "tcp");
+        tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for GatewayStreamListener {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        let mut shutdown = shutdown.clone();
+        // the listener stays registered, so it keeps trying until the
+        // address is free or its last route is removed
+        let mut retry = BIND_RETRY_MIN;
+        let listener = loop {
+            match TcpListener::bind(&self.addr).await {
+                Ok(listener) => break listener,
+                Err(e) => {
+                    error!(
+                        "Failed to bind tcp listener {}, retrying in {:?}: {:?}",
+                        self.addr, retry, e
+                    );
+                }
+            }
+            tokio::select! {
+                changed = shutdown.changed() => {
+                    if changed.is_err() || *shutdown.borrow() {
+                        return;
+                    }
+                }
+                _ = tokio::time::sleep(retry) => {}
+            }
+            retry = (retry * 2).min(BIND_RETRY_MAX);
+        };
+        info!("tcp listener listening on {}", self.addr);
+
+        let this = Arc::new(Self::new(&self.addr));
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    if *shutdown.borrow() {
+                        break;
+                    }
+                }
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, peer)) => {
+                            let this = this.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = this.handle(stream, peer).await {
+                                    error!("tcp connection from {} failed: {:?}", peer, e);
+                                }
+                            });
+                        }
+                        Err(e) => error!("tcp accept on {} failed: {:?}", self.addr, e),
+                    }
+                }
+            }
+        }
+        info!("tcp listener {} shutdown", self.addr);
+    }
+}
+
+/// Connect to a tcp or unix socket backend
+pub async fn connect_stream(addr: &SocketAddr) -> std::io::Result<Stream> {
+    match addr {
+        SocketAddr::Inet(addr) => TcpStream::connect(addr).await.map(Stream::from),
+        SocketAddr::Unix(addr) => {
+            let path = addr.as_pathname().ok_or(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "unnamed unix socket",
+            ))?;
+            UnixStream::connect(path).await.map(Stream::from)
+        }
+    }
+}
+
+/// Peek the TLS ClientHello without consuming it so the stream can be
+/// passed through to the upstream untouched.
+async fn peek_sni(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0u8; SNI_PEEK_BUFFER];
+    let peek = async {
+        loop {
+            let n = stream.peek(&mut buf).await.ok()?;
+            match parse_sni(&buf[..n]) {
+                SniParse::Incomplete if n > 0 && n < buf.len() => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                SniParse::Found(sni) => return Some(sni),
+                _ => return None,
+            }
+        }
+    };
+    tokio::time::timeout(SNI_PEEK_TIMEOUT, peek)
+        .await
+        .ok()
+        .flatten()
+}
+
+#[derive(Debug, PartialEq)]
+enum SniParse {
+    Found(String),
+    NotFound,
+    Incomplete,
+}
+
+fn parse_sni(buf: &[u8]) -> SniParse {
+    // TLS record header: content type, version, length
+    if buf.len() < 5 {
+        return SniParse::Incomplete;
+    }
+    if buf[0] != 0x16 {
+        return SniParse::NotFound;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + record_len {
+        return SniParse::Incomplete;
+    }
+    let hello = &buf[5..5 + record_len];
+    parse_client_hello(hello).map_or(SniParse::NotFound, SniParse::Found)
+}
+
+fn parse_client_hello(hello: &[u8]) -> Option<String> {
+    let mut reader = ByteReader(hello);
+    // handshake type ClientHello
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.skip(3)?; // handshake length
+    reader.skip(2 + 32)?; // client version, random
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression = reader.u8()? as usize;
+    reader.skip(compression)?;
+
+    let mut extensions = ByteReader(reader.prefixed()?);
+    while let Some(typ) = extensions.u16() {
+        let mut data = ByteReader(extensions.prefixed()?);
+        if typ != 0x0000 {
+            continue;
+        }
+        let mut names = ByteReader(data.prefixed()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.prefixed()?;
+            if name_type == 0x00 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|s| s.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// Read a u16 length followed by that many bytes
+    fn prefixed(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lb::{static_discovery, GatewayLoadBalancerOptions, GatewayMatchRule};
+
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut server_name = vec![];
+        server_name.extend((name.len() as u16 + 3).to_be_bytes());
+        server_name.push(0x00);
+        server_name.extend((name.len() as u16).to_be_bytes());
+        server_name.extend(name);
+
+        let mut extensions = vec![];
+        // an unrelated extension before server_name
+        extensions.extend([0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+        extensions.extend([0x00, 0x00]);
+        extensions.extend((server_name.len() as u16).to_be_bytes());
+        extensions.extend(server_name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.push(0x00); // session id
+        body.extend([0x00, 0x02, 0x13, 0x01]); // cipher suites
+        body.extend([0x01, 0x00]); // compression
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = client_hello("MQTT.example.com");
+
+        assert_eq!(
+            parse_sni(&hello),
+            SniParse::Found("mqtt.example.com".to_string())
+        );
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), SniParse::Incomplete);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), SniParse::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_update() {
+        let addr = "127.0.0.1:16399";
+        let add_route = |trusted: Option<&'static str>| async move {
+            let mut options = GatewayLoadBalancerOptions::new(
+                GatewayMatchRule::Any,
+                static_discovery(&[]).unwrap(),
+                false,
+            )
+            .with_listen(addr.to_string());
+            if let Some(trusted) = trusted {
+                let acceptor = ProxyProtocolAcceptor::new(vec![trusted.parse().unwrap()]);
+                options = options.with_proxy_protocol(acceptor);
+            }
+            let lb = Arc::new(GatewayLoadBalancer::new("pp", options));
+            crate::store::stream_routes()
+                .write()
+                .await
+                .insert("pp".to_string(), lb);
+        };
+        let listener = GatewayStreamListener::new(addr);
+        let local = "127.0.0.1".parse().unwrap();
+
+        add_route(None).await;
+        assert!(listener.proxy_protocol().await.is_none());
+        add_route(Some("127.0.0.0/8")).await;
+        assert!(listener.proxy_protocol().await.unwrap().is_trusted(local));
+        add_route(Some("10.0.0.0/8")).await;
+        assert!(!listener.proxy_protocol().await.unwrap().is_trusted(local));
+
+        crate::store::stream_routes().write().await.remove("pp");
+    }
+}